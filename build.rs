@@ -0,0 +1,61 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha1::{Digest, Sha1};
+
+// The embedded web client never changes once built, so we compress it once with the best settings
+// instead of doing it for every request.
+const PRECOMPRESSED_RESOURCES: [&str; 2] = ["index.html", "index.js"];
+// Filled with the hash of the web client, a page left open compares it with the one the server
+// sends when it reconnects
+const CLIENT_HASH_PLACEHOLDER: &str = "<meta name=\"twipo-client-hash\" content=\"\"/>";
+
+/// Same short hash as the ETags of the server, of the web client as it is in `res`.
+fn client_hash() -> String {
+    let mut hasher = Sha1::new();
+    for resource in PRECOMPRESSED_RESOURCES.iter() {
+        hasher.update(fs::read(Path::new("res").join(resource)).unwrap());
+    }
+    hasher.finalize()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let client_hash = client_hash();
+    println!("cargo:rustc-env=TWIPO_CLIENT_HASH={}", client_hash);
+    for resource in PRECOMPRESSED_RESOURCES.iter() {
+        let path = Path::new("res").join(resource);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let content = fs::read_to_string(&path).unwrap().replace(
+            CLIENT_HASH_PLACEHOLDER,
+            &format!(
+                "<meta name=\"twipo-client-hash\" content=\"{}\"/>",
+                client_hash
+            ),
+        );
+        fs::write(out_dir.join(resource), &content).unwrap();
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(content.as_bytes()).unwrap();
+        fs::write(
+            out_dir.join(format!("{}.gz", resource)),
+            gzip.finish().unwrap(),
+        )
+        .unwrap();
+
+        let mut brotli = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+        brotli.write_all(content.as_bytes()).unwrap();
+        fs::write(
+            out_dir.join(format!("{}.br", resource)),
+            brotli.into_inner(),
+        )
+        .unwrap();
+    }
+}
@@ -1,4 +1,6 @@
 const TAB_NAMES = ["a", "b", "c", "d"];
+// Version of the events and messages this page understands, see the `hello` event
+const PROTOCOL_VERSION = 1;
 
 let current_tab;
 function open_tab(tab_id, update_scroll) {
@@ -59,6 +61,73 @@ function format_text(input_text) {
 
 const DAY_TEXTS = ["Yesterday", "Today"];
 
+function create_author_div(pfp_id, username, realname) {
+	let author_div = document.createElement("div");
+	author_div.classList = "author";
+
+	let author_img = document.createElement("img");
+	author_img.src = image_url("img/pfp" + pfp_id.toString().padStart(2, "0") + ".png");
+
+	let author_name_div = document.createElement("div");
+	author_name_div.appendChild(format_text(username));
+	author_name_div.appendChild(document.createElement("br"));
+	author_name_div.appendChild(format_text(realname));
+
+	author_div.appendChild(author_img);
+	author_div.appendChild(author_name_div);
+	return author_div;
+}
+
+function close_profile() {
+	document.getElementById("profile").style.display = "none";
+}
+
+function open_profile(username) {
+	fetch("api/authors/" + encodeURIComponent(username)).then(function(response) {
+		if (!response.ok) {
+			throw new Error(response.status + " " + response.statusText);
+		}
+		return response.json();
+	}).then(function(authors) {
+		let profile = document.getElementById("profile");
+		profile.innerHTML = "";
+
+		let close_div = document.createElement("div");
+		close_div.classList = "profile_close";
+		close_div.innerText = "✕";
+		close_div.onclick = close_profile;
+		profile.appendChild(close_div);
+
+		for (let i in authors) {
+			let author = authors[i];
+			let header_div = document.createElement("div");
+			header_div.classList = "tweep";
+			header_div.appendChild(create_author_div(author.pfp_id, author.username, author.realname));
+			let count_div = document.createElement("div");
+			count_div.classList = "details";
+			count_div.innerText = author.tweep_count + " Tweeps";
+			header_div.appendChild(count_div);
+			profile.appendChild(header_div);
+
+			// Tweeps are sent in the order the game posted them, the newest must be on top
+			for (let j = author.tweeps.length - 1; j >= 0; j--) {
+				let tweep_div = document.createElement("div");
+				tweep_div.classList = "tweep";
+				tweep_div.appendChild(format_text(author.tweeps[j].content));
+				let details_div = document.createElement("div");
+				details_div.classList = "details";
+				details_div.innerText = DAY_TEXTS[Number(author.tweeps[j].post_date == window.game_date)];
+				tweep_div.appendChild(details_div);
+				profile.appendChild(tweep_div);
+			}
+		}
+		profile.scrollTop = 0;
+		profile.style.display = "block";
+	}).catch(function(e) {
+		console.log("Unable to open profile of " + username + " : " + e);
+	});
+}
+
 function add_tweep(tweep) {
 	let tab = document.getElementById("tab_" + TAB_NAMES[tweep.tab]);
 	// This is an ugly workaround since `scrollTop` and `offsetHeight` will not work with `display: none`
@@ -70,19 +139,12 @@ function add_tweep(tweep) {
 	let tweep_div = document.createElement("div");
 	tweep_div.classList = "tweep";
 
-	let author_div = document.createElement("div");
-	author_div.classList = "author";
-
-	let author_img = document.createElement("img");
-	author_img.src = "img/pfp" + tweep.pfp_id.toString().padStart(2, "0") + ".png";
-
-	let author_name_div = document.createElement("div");
-	author_name_div.appendChild(format_text(tweep.author_username));
-	author_name_div.appendChild(document.createElement("br"));
-	author_name_div.appendChild(format_text(tweep.author_realname));
-
-	author_div.appendChild(author_img);
-	author_div.appendChild(author_name_div);
+	let author_div = create_author_div(tweep.pfp_id, tweep.author_username, tweep.author_realname);
+	author_div.classList.add("author_link");
+	author_div.dataset.username = tweep.author_username.content;
+	author_div.onclick = function() {
+		open_profile(this.dataset.username);
+	};
 
 	let text_div = document.createElement("div");
 	text_div.appendChild(format_text(tweep.content));
@@ -96,6 +158,13 @@ function add_tweep(tweep) {
 	details_span.dataset.date = tweep.post_date;
 	details_div.appendChild(details_span);
 
+	let card_link = document.createElement("a");
+	card_link.classList = "details_link";
+	card_link.href = "card/" + tweep.id + ".png";
+	card_link.target = "_blank";
+	card_link.innerText = "Share";
+	details_div.appendChild(card_link);
+
 	let replies_div = null;
 	if (tweep.replies.length > 0) {
 		let replies_div_id = "replies_" + tweep.id.toString().padStart(8, "0");
@@ -112,7 +181,7 @@ function add_tweep(tweep) {
 		};
 
 		let reply_button_img = document.createElement("img");
-		reply_button_img.src = "img/reply.png";
+		reply_button_img.src = image_url("img/reply.png");
 		reply_button_div.appendChild(reply_button_img);
 
 		details_div.appendChild(reply_button_div);
@@ -142,7 +211,7 @@ function add_tweep(tweep) {
 			};
 
 			let send_button_img = document.createElement("img");
-			send_button_img.src = "img/send.png";
+			send_button_img.src = image_url("img/send.png");
 			send_button_div.appendChild(send_button_img);
 
 			reply_details_div.appendChild(send_button_div);
@@ -175,6 +244,22 @@ function add_tweep(tweep) {
 }
 
 function send_reply(tweep_id, reply_id) {
+	if (window.access != "paired") {
+		open_pairing();
+		return false;
+	}
+	if (window.transport != "websocket") {
+		fetch("api/tweeps/" + tweep_id + "/reply", {
+			method: "POST",
+			headers: {"Content-Type": "application/json"},
+			body: JSON.stringify({reply_id: reply_id}),
+		}).then(function(response) {
+			if (!response.ok) {
+				console.log("Unable to send the reply : " + response.status + " " + response.statusText);
+			}
+		});
+		return true;
+	}
 	if (window.websocket.readyState != window.WebSocket.OPEN) {
 		return false;
 	}
@@ -227,33 +312,149 @@ function update_date() {
 	}
 }
 
+// Hash of the web client this page was served with, a `hello` with another one means the server was
+// updated since the page was loaded
+const CLIENT_HASH = document.querySelector('meta[name="twipo-client-hash"]').content;
+function check_hello(hello) {
+	let stale = hello.protocol_version != PROTOCOL_VERSION || hello.client_hash != CLIENT_HASH;
+	if (stale && !window.stale_warned) {
+		window.stale_warned = true;
+		alert("twipo-synchro was updated to version " + hello.server_version + ", refresh the page to use the new version.");
+	}
+}
+
+// Sequence number of the last event received, a reconnection only gets the events missed since
+window.last_seq = null;
+function since_query() {
+	return window.last_seq === null ? "" : "since=" + window.last_seq;
+}
+
+function handle_message(message) {
+	if (message.seq !== undefined) {
+		window.last_seq = message.seq;
+	}
+	if (message.type == "hello") {
+		check_hello(message);
+	} else if (message.type == "clear") {
+		clear_tweeps();
+	} else if (message.type == "tweep") {
+		add_tweep(message.tweep);
+	} else if (message.type == "set_reply_possible") {
+		set_reply_possible(message.tweep_id, message.possible);
+	} else if (message.type == "reload_images") {
+		reload_images(message.urls);
+	} else if (message.type == "date") {
+		window.game_date = message.date;
+		update_date();
+	} else if (message.type == "snapshot") {
+		console.log("Received the whole timeline");
+	} else if (message.type == "error") {
+		console.log("Message refused by the server : " + message.code + " : " + message.message);
+		if (message.code == "not_paired") {
+			open_pairing();
+		}
+	} else if (message.type == "pong") {
+		// Only sent when asked, nothing to do
+	} else {
+		alert("Unknown message : " + JSON.stringify(message));
+	}
+}
+
+// Delay before reconnecting a WebSocket that was open, doubled after each failed attempt. The page
+// gives up after `MAX_RECONNECTS` attempts in a row.
+const RECONNECT_DELAY = 500;
+const MAX_RECONNECT_DELAY = 30000;
+const MAX_RECONNECTS = 10;
+window.websocket_failures = 0;
+window.websocket_retry_at = 0;
+
 function connect_websocket() {
-	clear_tweeps();
 	console.log("(re)connecting to websocket");
-	let websocket = new WebSocket("ws://" + location.host + "/websocket");
+	let websocket_url = new URL("websocket", document.baseURI);
+	websocket_url.search = since_query();
+	websocket_url.protocol = location.protocol == "https:" ? "wss:" : "ws:";
+	let websocket = new WebSocket(websocket_url);
+	window.transport = "websocket";
 	window.websocket = websocket;
 	window.websocketfailed = false;
+	websocket.onopen = function() {
+		window.websocketopened = true;
+		window.websocket_failures = 0;
+		websocket.send(JSON.stringify({type: "hello", client: "twipo-synchro web client", capabilities: []}));
+	};
 	websocket.onmessage = function(e) {
-		let message = JSON.parse(e.data);
-		if (message.type == "clear") {
-			clear_tweeps();
-		} else if (message.type == "tweep") {
-			add_tweep(message.tweep);
-		} else if (message.type == "set_reply_possible") {
-			set_reply_possible(message.tweep_id, message.possible);
-		} else if (message.type == "date") {
-			window.game_date = message.date;
-			update_date();
-		} else {
-			alert("Unknown message : " + e.data);
-		}
+		handle_message(JSON.parse(e.data));
 	};
 	websocket.onclose = function(e) {
-		alert("WebSocket closed : " + e.code + " " + e.reason + "\n" + "You can refresh the page to reconnect.");
-		window.websocketfailed = true;
+		// Some proxies and old browsers can't open WebSockets at all, the events can still be
+		// received over plain HTTP
+		if (!window.websocketopened) {
+			window.websocketfailed = true;
+			if (window.EventSource) {
+				connect_event_source();
+			} else {
+				poll_events(null);
+			}
+			return;
+		}
+		// The server was restarted, we fell too far behind (1013 "Try Again Later") or the connection
+		// was lost : the timer reconnects and gets the events we missed
+		window.websocket_failures++;
+		if (window.websocket_failures > MAX_RECONNECTS) {
+			window.websocketfailed = true;
+			alert("WebSocket closed : " + e.code + " " + e.reason + "\n" + "You can refresh the page to reconnect.");
+			return;
+		}
+		let delay = Math.min(RECONNECT_DELAY * 2 ** (window.websocket_failures - 1), MAX_RECONNECT_DELAY);
+		window.websocket_retry_at = Date.now() + delay;
+		console.log("WebSocket closed : " + e.code + " " + e.reason + ", reconnecting in " + delay + " ms");
 	};
 }
 
+function connect_event_source() {
+	console.log("WebSocket unavailable, using Server-Sent Events");
+	window.transport = "events";
+	// The browser reconnects by itself with the id of the last event, which the server prefers to
+	// the `since` of the first connection
+	let query = since_query();
+	let events = new EventSource(query == "" ? "events" : "events?" + query);
+	events.onmessage = function(e) {
+		handle_message(JSON.parse(e.data));
+	};
+}
+
+function poll_events(session) {
+	window.transport = "poll";
+	let url = session === null ? "api/poll?" + since_query() : "api/poll?session=" + encodeURIComponent(session);
+	fetch(url).then(function(response) {
+		// The session expired or we were too slow, a new one starts with the events we missed
+		if (response.status == 404) {
+			return {session: null, snapshot: false, events: []};
+		}
+		// The device isn't paired anymore, polling goes on once it is
+		if (response.status == 403) {
+			return response.json().then(function(error) {
+				if (error.error == "not_paired" && document.getElementById("pairing").style.display != "flex") {
+					open_pairing();
+				}
+				throw new Error(error.error);
+			});
+		}
+		if (!response.ok) {
+			throw new Error(response.status + " " + response.statusText);
+		}
+		return response.json();
+	}).then(function(poll) {
+		for (let i in poll.events) {
+			handle_message(poll.events[i]);
+		}
+		poll_events(poll.session);
+	}).catch(function(e) {
+		console.log("Polling failed : " + e.message);
+		setTimeout(function() { poll_events(session); }, 1000);
+	});
+}
+
 let touch_start = 0;
 document.addEventListener("touchstart", function(e) {
 	touch_start = e.changedTouches[0].screenX;
@@ -266,12 +467,86 @@ document.addEventListener("touchend", function(e) {
 	}
 });
 
-document.addEventListener('DOMContentLoaded', function() {
-	open_tab(0, false);
+let sprite_prefix = "";
+function load_themed_sprites() {
+	// The prefix is a CSS string so it comes with its quotes
+	sprite_prefix = getComputedStyle(document.documentElement).getPropertyValue("--sprite-prefix").trim().replace(/"/g, "");
+	let sprites = document.querySelectorAll("img[data-sprite]");
+	for (let i = 0; i < sprites.length; i++) {
+		sprites[i].src = image_url("img/" + sprite_prefix + sprites[i].dataset.sprite);
+	}
+}
+
+// Versioned URLs of the images, indexed by their base URL. Every snapshot starts with all of them, the
+// sprites are only loaded once they are known so the browser can keep them in cache.
+let image_urls = {};
+function image_url(url) {
+	return image_urls[url] || url;
+}
+
+function reload_images(urls) {
+	for (let i in urls) {
+		image_urls[urls[i].split("?")[0]] = urls[i];
+	}
+	load_themed_sprites();
+
+	let images = document.getElementsByTagName("img");
+	for (let i = 0; i < images.length; i++) {
+		let url = images[i].getAttribute("src").split("?")[0];
+		if (url in image_urls) {
+			images[i].src = image_urls[url];
+		}
+	}
+
+	let bg_url = "img/" + sprite_prefix + "bg.png";
+	if (bg_url in image_urls) {
+		document.body.style.backgroundImage = 'url("' + image_urls[bg_url] + '")';
+	}
+}
+
+function open_pairing() {
+	document.getElementById("pairing_error").innerText = "";
+	document.getElementById("pairing").style.display = "flex";
+	document.getElementById("pairing_pin").focus();
+}
+
+function submit_pairing_pin() {
+	let pin = document.getElementById("pairing_pin").value;
+	fetch("api/pairing", {
+		method: "POST",
+		headers: {"Content-Type": "application/json"},
+		body: JSON.stringify({pin: pin}),
+	}).then(function(response) {
+		if (response.status == 403) {
+			throw new Error("Wrong PIN, check the console for the current one");
+		} else if (response.status == 429) {
+			throw new Error("Too many wrong PINs, try again in " + response.headers.get("Retry-After") + " seconds");
+		} else if (!response.ok) {
+			throw new Error(response.status + " " + response.statusText);
+		}
+		return response.json();
+	}).then(function(pairing) {
+		let was_denied = window.access == "denied";
+		window.access = pairing.access;
+		document.getElementById("pairing").style.display = "none";
+		if (was_denied) {
+			start_timeline();
+		} else if (window.transport == "websocket") {
+			// The WebSocket was opened before pairing, it must be reopened with the session cookie, the
+			// timer will do it since it is not open anymore
+			window.websocket.onclose = null;
+			window.websocket.close();
+		}
+	}).catch(function(e) {
+		document.getElementById("pairing_error").innerText = e.message;
+	});
+}
+
+function start_timeline() {
 	connect_websocket();
 
 	setInterval(function() {
-		if (window.websocket.readyState != window.WebSocket.OPEN && !window.websocketfailed) {
+		if (window.websocket.readyState == window.WebSocket.CLOSED && !window.websocketfailed && Date.now() >= window.websocket_retry_at) {
 			// We make sure the old websocket does not interfere with the new one
 			window.websocket.onmessage = null;
 			window.websocket.onclose = null;
@@ -281,4 +556,26 @@ document.addEventListener('DOMContentLoaded', function() {
 			connect_websocket();
 		}
 	}, 500);
+}
+
+document.addEventListener('DOMContentLoaded', function() {
+	open_tab(0, false);
+	document.getElementById("pairing_pin").addEventListener("keydown", function(e) {
+		if (e.key == "Enter") {
+			submit_pairing_pin();
+		}
+	});
+
+	fetch("api/pairing").then(function(response) {
+		return response.json();
+	}).then(function(pairing) {
+		window.access = pairing.access;
+		if (window.access == "denied") {
+			// No timeline, so no versioned URLs until the device is paired
+			load_themed_sprites();
+			open_pairing();
+		} else {
+			start_timeline();
+		}
+	});
 });
@@ -0,0 +1,72 @@
+use async_std::sync::{Arc, Mutex};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::json;
+
+use super::game::{SC3String, Tweep};
+
+pub struct Author {
+    pub username: SC3String,
+    pub realname: SC3String,
+    pub pfp_id: u16,
+    pub tab: u8,
+    pub tweep_ids: BTreeSet<u32>,
+}
+
+impl Author {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "username": self.username,
+            "realname": self.realname,
+            "pfp_id": self.pfp_id,
+            "avatar": format!("img/pfp{:02}.png", self.pfp_id),
+            "tab": self.tab,
+            "tweep_count": self.tweep_ids.len(),
+        })
+    }
+}
+
+/// Every author seen in a tweep since the server started. When the game clears the timeline the
+/// authors are kept but lose their tweeps, so their count matches the timeline. The same username
+/// may be used with several avatars so both are used as the key.
+#[derive(Default)]
+pub struct AuthorDirectory {
+    authors: BTreeMap<(String, u16), Author>,
+}
+
+impl AuthorDirectory {
+    pub fn add_tweep(&mut self, tweep: &Tweep) {
+        let key = (tweep.author_username.content.clone(), tweep.pfp_id);
+        let author = self.authors.entry(key).or_insert_with(|| Author {
+            username: tweep.author_username.clone(),
+            realname: tweep.author_realname.clone(),
+            pfp_id: tweep.pfp_id,
+            tab: tweep.tab,
+            tweep_ids: BTreeSet::new(),
+        });
+        // The latest tweep is the most likely to reflect the current state of the game
+        author.realname = tweep.author_realname.clone();
+        author.tab = tweep.tab;
+        author.tweep_ids.insert(tweep.id);
+    }
+
+    /// Forgets the tweeps of every author, called when the game clears the timeline.
+    pub fn clear_tweeps(&mut self) {
+        for author in self.authors.values_mut() {
+            author.tweep_ids.clear();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Author> {
+        self.authors.values()
+    }
+
+    pub fn find_by_username<'a>(&'a self, username: &'a str) -> impl Iterator<Item = &'a Author> {
+        self.authors
+            .values()
+            .filter(move |author| author.username.content == username)
+    }
+}
+
+pub type Authors = Arc<Mutex<AuthorDirectory>>;
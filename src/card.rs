@@ -0,0 +1,219 @@
+use image::imageops::{self, FilterType};
+use image::{ImageFormat, Rgba, RgbaImage};
+
+use std::error::Error;
+
+use super::game::{SC3Op, SC3String, Tweep};
+use super::images::ImageList;
+
+const CARD_WIDTH: u32 = 613;
+const PADDING: u32 = 20;
+const AVATAR_SIZE: u32 = 96;
+// Unifont glyphs are 16px high bitmaps, we scale them with the nearest neighbour to keep them sharp
+const GLYPH_SCALE: u32 = 2;
+const GLYPH_HEIGHT: u32 = 16;
+const LINE_HEIGHT: u32 = (GLYPH_HEIGHT + 2) * GLYPH_SCALE;
+
+const TEXT_COLOR: Rgba<u8> = Rgba([0x40, 0x40, 0x40, 0xFF]);
+const AUTHOR_COLOR: Rgba<u8> = Rgba([0x31, 0x49, 0x81, 0xFF]);
+const RUBY_COLOR: Rgba<u8> = Rgba([0x00, 0x00, 0xFF, 0xFF]);
+const DETAILS_COLOR: Rgba<u8> = Rgba([0xA0, 0xA0, 0xA0, 0xFF]);
+const BORDER_COLOR: Rgba<u8> = Rgba([0x80, 0x80, 0x80, 0xFF]);
+
+/// Every character of a string with the colour it should be drawn with, linebreaks are kept as `\n`.
+type StyledText = Vec<(char, Rgba<u8>)>;
+
+fn style_text(text: &SC3String, color: Rgba<u8>) -> StyledText {
+    let mut output = StyledText::with_capacity(text.content.len());
+    let mut markers = text.markers.iter().peekable();
+    let mut current_color = color;
+    for (offset, character) in text.content.chars().enumerate() {
+        while let Some(marker) = markers.next_if(|marker| marker.offset() <= offset) {
+            match marker {
+                SC3Op::Linebreak(_) => output.push(('\n', current_color)),
+                SC3Op::RubyBase(_) => current_color = RUBY_COLOR,
+                SC3Op::RubyEnd(_) => current_color = color,
+            }
+        }
+        output.push((character, current_color));
+    }
+    output
+}
+
+fn glyph_width(character: char) -> u32 {
+    unifont::get_glyph(character).map_or(8, |glyph| glyph.get_width() as u32) * GLYPH_SCALE
+}
+
+/// Splits the text in lines fitting in `max_width`, breaking on spaces when possible since most of
+/// the translated tweeps are in English.
+fn wrap_text(text: &[(char, Rgba<u8>)], max_width: u32) -> Vec<&[(char, Rgba<u8>)]> {
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let mut line_width = 0;
+    let mut last_space: Option<usize> = None;
+    for (index, (character, _)) in text.iter().enumerate() {
+        if *character == '\n' {
+            lines.push(&text[line_start..index]);
+            (line_start, line_width, last_space) = (index + 1, 0, None);
+            continue;
+        }
+
+        let width = glyph_width(*character);
+        if line_width + width > max_width && index > line_start {
+            let break_at = last_space.unwrap_or(index);
+            lines.push(&text[line_start..break_at]);
+            line_start = if last_space.is_some() {
+                break_at + 1
+            } else {
+                break_at
+            };
+            line_width = text[line_start..index]
+                .iter()
+                .map(|(c, _)| glyph_width(*c))
+                .sum();
+            last_space = text[line_start..index]
+                .iter()
+                .rposition(|(c, _)| *c == ' ')
+                .map(|position| line_start + position);
+        }
+        if *character == ' ' {
+            last_space = Some(index);
+        }
+        line_width += width;
+    }
+    if line_start < text.len() {
+        lines.push(&text[line_start..]);
+    }
+    lines
+}
+
+fn draw_text(card: &mut RgbaImage, text: &[(char, Rgba<u8>)], x: u32, y: u32) {
+    let mut pen_x = x;
+    for (character, color) in text.iter() {
+        if let Some(glyph) = unifont::get_glyph(*character) {
+            for glyph_y in 0..GLYPH_HEIGHT as usize {
+                for glyph_x in 0..glyph.get_width() {
+                    if !glyph.get_pixel(glyph_x, glyph_y) {
+                        continue;
+                    }
+                    for dy in 0..GLYPH_SCALE {
+                        for dx in 0..GLYPH_SCALE {
+                            let pixel_x = pen_x + glyph_x as u32 * GLYPH_SCALE + dx;
+                            let pixel_y = y + glyph_y as u32 * GLYPH_SCALE + dy;
+                            if let Some(pixel) = card.get_pixel_mut_checked(pixel_x, pixel_y) {
+                                *pixel = *color;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        pen_x += glyph_width(*character);
+    }
+}
+
+fn draw_rectangle(card: &mut RgbaImage, x: u32, y: u32, w: u32, h: u32, color: Rgba<u8>) {
+    for px in x..x + w {
+        card.put_pixel(px, y, color);
+        card.put_pixel(px, y + h - 1, color);
+    }
+    for py in y..y + h {
+        card.put_pixel(x, py, color);
+        card.put_pixel(x + w - 1, py, color);
+    }
+}
+
+/// Name of the avatar of `tweep` in the image list.
+fn avatar_name(tweep: &Tweep) -> String {
+    format!("pfp{:02}.png", tweep.pfp_id)
+}
+
+/// Copies the images used by the card of `tweep`, so it can be rendered without holding the list.
+pub fn card_images(tweep: &Tweep, image_list: &ImageList) -> ImageList {
+    ["bg.png".to_string(), avatar_name(tweep)]
+        .into_iter()
+        .filter_map(|name| {
+            let image = image_list.get(&name)?.clone();
+            Some((name, image))
+        })
+        .collect()
+}
+
+/// Renders a tweep as a PNG image looking like the in-game Twipo, `date` is the current in-game date
+/// used to tell if the tweep was posted today.
+pub fn render_card(
+    tweep: &Tweep,
+    date: u32,
+    image_list: &ImageList,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let text_width = CARD_WIDTH - 2 * PADDING;
+    let content = style_text(&tweep.content, TEXT_COLOR);
+    let lines = wrap_text(&content, text_width);
+
+    let content_y = 2 * PADDING + AVATAR_SIZE;
+    let details_y = content_y + lines.len() as u32 * LINE_HEIGHT + PADDING / 2;
+    let height = details_y + LINE_HEIGHT + PADDING;
+
+    let mut card = match image_list.get("bg.png") {
+        Some(bg) => image::load_from_memory_with_format(&bg.data, ImageFormat::Png)?
+            .resize_to_fill(CARD_WIDTH, height, FilterType::Triangle)
+            .to_rgba8(),
+        None => RgbaImage::from_pixel(CARD_WIDTH, height, Rgba([0xFF, 0xFF, 0xFF, 0xFF])),
+    };
+    draw_rectangle(
+        &mut card,
+        PADDING / 2,
+        PADDING / 2,
+        CARD_WIDTH - PADDING,
+        height - PADDING,
+        BORDER_COLOR,
+    );
+
+    if let Some(avatar) = image_list.get(&avatar_name(tweep)) {
+        let avatar = image::load_from_memory_with_format(&avatar.data, ImageFormat::Png)?.resize(
+            AVATAR_SIZE,
+            AVATAR_SIZE,
+            FilterType::Triangle,
+        );
+        imageops::overlay(&mut card, &avatar, PADDING as i64, PADDING as i64);
+    }
+
+    let names_x = 2 * PADDING + AVATAR_SIZE;
+    let names_y = PADDING + (AVATAR_SIZE - 2 * LINE_HEIGHT) / 2;
+    for (index, name) in [&tweep.author_username, &tweep.author_realname]
+        .iter()
+        .enumerate()
+    {
+        let name = style_text(name, AUTHOR_COLOR);
+        // Names are expected to fit on a single line, the end is cut if they don't
+        if let Some(first_line) = wrap_text(&name, CARD_WIDTH - names_x - PADDING).first() {
+            draw_text(
+                &mut card,
+                first_line,
+                names_x,
+                names_y + index as u32 * LINE_HEIGHT,
+            );
+        }
+    }
+
+    for (index, line) in lines.iter().enumerate() {
+        draw_text(
+            &mut card,
+            line,
+            PADDING,
+            content_y + index as u32 * LINE_HEIGHT,
+        );
+    }
+
+    let day_text = if tweep.post_date == date {
+        "Today"
+    } else {
+        "Yesterday"
+    };
+    let details: StyledText = day_text.chars().map(|c| (c, DETAILS_COLOR)).collect();
+    draw_text(&mut card, &details, PADDING, details_y);
+
+    let mut buff = std::io::Cursor::new(Vec::with_capacity(0x10000));
+    card.write_to(&mut buff, ImageFormat::Png)?;
+    Ok(buff.into_inner())
+}
@@ -0,0 +1,90 @@
+use serde::Deserialize;
+
+use std::io::{Error as IoError, Write};
+use std::path::{Path, PathBuf};
+
+/// Options read from `config.json` next to the server executable. Every key is optional, the listen
+/// address is still provided by LanguageBarrier on the command line.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    pub detect_avatar_grid: bool,
+    pub allowed_hosts: Vec<String>,
+    pub pairing: bool,
+    pub read_only_unpaired: bool,
+    pub tls: bool,
+    pub tls_certificate: Option<String>,
+    pub tls_key: Option<String>,
+    pub mdns: bool,
+    pub mdns_hostname: String,
+    pub base_path: String,
+    pub trusted_proxies: Vec<String>,
+    pub ping_interval: u64,
+    pub ping_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            detect_avatar_grid: false,
+            allowed_hosts: Vec::new(),
+            pairing: true,
+            read_only_unpaired: false,
+            tls: false,
+            tls_certificate: None,
+            tls_key: None,
+            mdns: true,
+            mdns_hostname: "twipo".to_string(),
+            base_path: String::new(),
+            trusted_proxies: Vec::new(),
+            ping_interval: 20,
+            ping_timeout: 20,
+        }
+    }
+}
+
+/// Path of a file stored next to the server executable, in the `twipo-synchro` folder of the game.
+pub fn data_path(name: &str) -> Option<PathBuf> {
+    Some(std::env::current_exe().ok()?.parent()?.join(name))
+}
+
+/// Writes a secret such as a key, on unix only the user running the server can read it.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), IoError> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode is only used when the file is created
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let path = match data_path("config.json") {
+            Some(p) if p.is_file() => p,
+            _ => return Config::default(),
+        };
+
+        let config = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()));
+        match config {
+            Ok(c) => {
+                eprintln!("Configuration loaded from {}", path.display());
+                c
+            }
+            Err(e) => {
+                // A broken configuration shouldn't prevent the game from starting
+                eprintln!(
+                    "Unable to read {}, using the default configuration : {}",
+                    path.display(),
+                    e
+                );
+                Config::default()
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::io::{Error as IoError, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+// Dynamic bodies are compressed for every request, so we favour speed over size
+const BROTLI_DYNAMIC_QUALITY: u32 = 4;
+const BROTLI_WINDOW: u32 = 22;
+// Below this size the compression headers cost more than what we could save
+pub const MINIMUM_COMPRESSED_SIZE: usize = 256;
+
+impl Encoding {
+    /// Picks the encoding with the highest quality in the value of an `Accept-Encoding` header,
+    /// brotli is preferred over gzip when both have the same. A coding listed explicitly takes
+    /// precedence over `*`, whatever their order.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+        let quality = |name: &str| {
+            let mut explicit: Option<f32> = None;
+            let mut wildcard: Option<f32> = None;
+            for coding in accept_encoding.unwrap_or("").split(',') {
+                let mut parameters = coding.split(';').map(|p| p.trim());
+                let coding_name = parameters.next().unwrap_or("");
+                let quality = parameters
+                    .find_map(|p| p.strip_prefix("q="))
+                    .map(|q| q.parse::<f32>().unwrap_or(0.0))
+                    .unwrap_or(1.0);
+                let best = if coding_name.eq_ignore_ascii_case(name) {
+                    &mut explicit
+                } else if coding_name == "*" {
+                    &mut wildcard
+                } else {
+                    continue;
+                };
+                *best = Some(best.map_or(quality, |best: f32| best.max(quality)));
+            }
+            explicit.or(wildcard).unwrap_or(0.0)
+        };
+
+        let (brotli, gzip) = (quality("br"), quality("gzip"));
+        if brotli > 0.0 && brotli >= gzip {
+            Encoding::Brotli
+        } else if gzip > 0.0 {
+            Encoding::Gzip
+        } else {
+            Encoding::Identity
+        }
+    }
+
+    /// Value of the `Content-Encoding` header, `None` when the body is sent as is.
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, IoError> {
+        match self {
+            Encoding::Identity => Ok(data.to_vec()),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(
+                    Vec::new(),
+                    4096,
+                    BROTLI_DYNAMIC_QUALITY,
+                    BROTLI_WINDOW,
+                );
+                encoder.write_all(data)?;
+                Ok(encoder.into_inner())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+
+    #[test]
+    fn negotiate_without_header() {
+        assert_eq!(Encoding::negotiate(None), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(Some("")), Encoding::Identity);
+    }
+
+    #[test]
+    fn negotiate_prefers_brotli() {
+        assert_eq!(
+            Encoding::negotiate(Some("gzip, deflate, br")),
+            Encoding::Brotli
+        );
+        assert_eq!(Encoding::negotiate(Some("gzip")), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate(Some("*")), Encoding::Brotli);
+    }
+
+    #[test]
+    fn negotiate_compares_qualities() {
+        assert_eq!(
+            Encoding::negotiate(Some("gzip;q=1, br;q=0.1")),
+            Encoding::Gzip
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("gzip;q=0.5, br;q=0.8")),
+            Encoding::Brotli
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("br;q=0.2, gzip;q=0.2")),
+            Encoding::Brotli
+        );
+    }
+
+    #[test]
+    fn negotiate_explicit_refusal_wins_over_wildcard() {
+        assert_eq!(Encoding::negotiate(Some("br;q=0, *")), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate(Some("*, br;q=0")), Encoding::Gzip);
+        assert_eq!(
+            Encoding::negotiate(Some("br;q=0, gzip;q=0, *")),
+            Encoding::Identity
+        );
+        assert_eq!(Encoding::negotiate(Some("*;q=0")), Encoding::Identity);
+    }
+
+    #[test]
+    fn negotiate_keeps_the_highest_quality_of_a_coding() {
+        assert_eq!(
+            Encoding::negotiate(Some("br;q=0, br;q=0.5, gzip;q=0.4")),
+            Encoding::Brotli
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("BR;q=0.1, gzip;q=invalid")),
+            Encoding::Brotli
+        );
+    }
+}
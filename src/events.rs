@@ -0,0 +1,624 @@
+use async_std::future;
+use async_std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::channel::mpsc;
+use futures::future::Either;
+use futures::prelude::*;
+
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+use async_tungstenite::WebSocketStream;
+use tungstenite::error::Error as WsError;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use super::config::Config;
+use super::game::{Date, Tweeps};
+use super::images::Images;
+use super::pairing;
+use super::tls::Stream;
+
+// A client further behind than this is dropped, it gets a new snapshot when it reconnects
+const QUEUE_SIZE: usize = 256;
+// Events kept for the clients reconnecting, a game day rarely has more
+const EVENT_LOG_SIZE: usize = 1024;
+// A client that doesn't accept a single event for this long is considered gone
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+// Below the usual timeouts of proxies, which close idle requests after a minute or so
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+const POLL_SESSION_EXPIRY: Duration = Duration::from_secs(60);
+const POLL_SESSION_ID_SIZE: usize = 16;
+// Seconds a WebSocket client has to answer a ping, a shorter `pingTimeout` would disconnect every
+// client that isn't right next to the computer
+const MIN_PING_TIMEOUT: u64 = 5;
+
+// Incremented when the events or the client messages change in a way older clients can't handle
+const PROTOCOL_VERSION: u32 = 1;
+const GAME_PROFILE: &str = "robotics_notes_elite";
+// Tabs of Twipo in the order of the `tab` of the tweeps, with the sprite of their selector
+const TABS: [(&str, &str); 4] = [
+    ("a", "sel_a.png"),
+    ("b", "sel_b.png"),
+    ("c", "sel_c.png"),
+    ("d", "sel_d.png"),
+];
+
+/// Event sent to the clients, serialized once and shared by every queue.
+pub struct Event {
+    /// Only the events of the log have one, plus the first and last events of a snapshot.
+    pub seq: Option<u64>,
+    pub json: String,
+}
+
+impl Event {
+    fn new(seq: Option<u64>, event: serde_json::Value) -> Arc<Event> {
+        Arc::new(Event {
+            seq,
+            json: event.to_string(),
+        })
+    }
+}
+
+/// Queue of a new client and what it must receive first.
+pub struct Subscription {
+    pub events: Vec<Arc<Event>>,
+    /// Whether `events` is a whole snapshot rather than the events missed since the last one known
+    pub snapshot: bool,
+    pub receiver: mpsc::Receiver<Arc<Event>>,
+}
+
+pub struct HubState {
+    clients: Vec<mpsc::Sender<Arc<Event>>>,
+    next_seq: u64,
+    log: VecDeque<Arc<Event>>,
+}
+
+/// Sends the events of the game to every client. Each client has its own bounded queue emptied by
+/// its own task, so a slow client delays neither the game nor the other clients. A client whose
+/// queue is full is dropped, its queue then ends and it must reconnect to get a new snapshot.
+///
+/// The latest events are kept with their sequence number, a client reconnecting only gets the ones
+/// it missed if they are still there.
+pub struct Hub {
+    state: Mutex<HubState>,
+    // Sent first to every client, see `hello_event`
+    hello: Arc<Event>,
+}
+
+/// First event of every client, describing the server so third-party clients can adapt and the web
+/// client notices when it is older than the server. `client_hash` changes with the web client.
+pub fn hello_event(config: &Config, tls: bool, client_hash: &str) -> String {
+    let tabs: Vec<serde_json::Value> = TABS
+        .iter()
+        .enumerate()
+        .map(|(index, (name, sprite))| json!({"index": index, "name": name, "sprite": sprite}))
+        .collect();
+    let features: Vec<&str> = [
+        ("pairing", config.pairing),
+        (
+            "read_only_unpaired",
+            config.pairing && config.read_only_unpaired,
+        ),
+        ("tls", tls),
+        ("mdns", config.mdns),
+        ("ping", config.ping_interval > 0),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect();
+    json!({
+        "type": "hello",
+        "server_version": env!("CARGO_PKG_VERSION"),
+        "protocol_version": PROTOCOL_VERSION,
+        "client_hash": client_hash,
+        "game": GAME_PROFILE,
+        "tab_count": TABS.len(),
+        "tabs": tabs,
+        "sprites": {"header": "header.png", "unselected": "unsel.png"},
+        "features": features,
+    })
+    .to_string()
+}
+
+impl HubState {
+    /// Numbers `event`, keeps it in the log and queues it for every client.
+    pub fn publish(&mut self, mut event: serde_json::Value) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        event["seq"] = json!(seq);
+        let event = Event::new(Some(seq), event);
+        if self.log.len() >= EVENT_LOG_SIZE {
+            self.log.pop_front();
+        }
+        self.log.push_back(event.clone());
+        self.clients
+            .retain_mut(|client| client.try_send(event.clone()).is_ok());
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Events published after `since`, `None` if some of them are not in the log anymore.
+    fn events_since(&self, since: u64) -> Option<Vec<Arc<Event>>> {
+        let oldest = self
+            .log
+            .front()
+            .and_then(|event| event.seq)
+            .unwrap_or(self.next_seq);
+        if since.saturating_add(1) < oldest || since > self.last_seq() {
+            return None;
+        }
+        Some(
+            self.log
+                .iter()
+                .filter(|event| event.seq.is_some_and(|seq| seq > since))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Events bringing a new client up to date : the versioned URLs of every image, the date and
+    /// every tweep of the timeline, between a `clear` and a `snapshot` event. The `clear` has the
+    /// sequence number 0, older than any event of the log, so a client disconnected in the middle
+    /// of the snapshot gets a new one.
+    async fn snapshot(&self, tweeps: &Tweeps, date: &Date, images: &Images) -> Vec<Arc<Event>> {
+        let mut urls: Vec<String> = images
+            .read()
+            .await
+            .iter()
+            .map(|(name, image)| image.versioned_url(name))
+            .collect();
+        urls.sort();
+        let mut events = vec![
+            Event::new(Some(0), json!({"type": "clear", "seq": 0})),
+            Event::new(None, json!({"type": "reload_images", "urls": urls})),
+            Event::new(None, json!({"type": "date", "date": *date.read().await})),
+        ];
+        for tweep in tweeps.lock().await.iter() {
+            events.push(Event::new(None, json!({"type": "tweep", "tweep": tweep})));
+        }
+        let seq = self.last_seq();
+        events.push(Event::new(
+            Some(seq),
+            json!({"type": "snapshot", "seq": seq}),
+        ));
+        events
+    }
+}
+
+impl Hub {
+    pub fn new(hello: String) -> Hub {
+        // Starting from the current time, the numbers given by a previous run of the server are
+        // always older than the log
+        let next_seq = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(1);
+        Hub {
+            state: Mutex::new(HubState {
+                clients: Vec::new(),
+                next_seq,
+                log: VecDeque::with_capacity(EVENT_LOG_SIZE),
+            }),
+            hello: Arc::new(Event {
+                seq: None,
+                json: hello,
+            }),
+        }
+    }
+
+    /// The game reader holds the hub while it changes the timeline and publishes the event, so a
+    /// snapshot can't contain a change whose event isn't published yet.
+    pub async fn lock(&self) -> MutexGuard<'_, HubState> {
+        self.state.lock().await
+    }
+
+    /// Creates the queue of a new client along with the `hello` event and the events it missed
+    /// since `since`, or a snapshot if they are not all in the log.
+    pub async fn subscribe(
+        &self,
+        tweeps: &Tweeps,
+        date: &Date,
+        images: &Images,
+        since: Option<u64>,
+    ) -> Subscription {
+        self.subscribe_after(vec![self.hello.clone()], tweeps, date, images, since)
+            .await
+    }
+
+    /// Creates a new queue starting with a snapshot for a client asking for the whole timeline
+    /// again, without the `hello` event it already has.
+    pub async fn resubscribe(&self, tweeps: &Tweeps, date: &Date, images: &Images) -> Subscription {
+        self.subscribe_after(Vec::new(), tweeps, date, images, None)
+            .await
+    }
+
+    /// Creates a queue whose first events are `events` followed by the events missed since `since`
+    /// or a snapshot. The hub is locked in between so no event can be missed.
+    async fn subscribe_after(
+        &self,
+        mut events: Vec<Arc<Event>>,
+        tweeps: &Tweeps,
+        date: &Date,
+        images: &Images,
+        since: Option<u64>,
+    ) -> Subscription {
+        let mut state = self.state.lock().await;
+        let snapshot = match since.and_then(|since| state.events_since(since)) {
+            Some(missed) => {
+                events.extend(missed);
+                false
+            }
+            None => {
+                events.extend(state.snapshot(tweeps, date, images).await);
+                true
+            }
+        };
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        state.clients.push(sender);
+        Subscription {
+            events,
+            snapshot,
+            receiver,
+        }
+    }
+}
+
+/// WebSocket pings sent by the server. A client gone without closing its connection, like a phone
+/// that lost the Wi-Fi, doesn't answer them and is disconnected instead of waiting for an event to
+/// fail.
+#[derive(Clone, Copy)]
+pub struct Keepalive {
+    // `None` when the pings are disabled
+    interval: Option<Duration>,
+    timeout: Duration,
+}
+
+impl Keepalive {
+    pub fn new(config: &Config) -> Keepalive {
+        let timeout = if config.ping_timeout < MIN_PING_TIMEOUT {
+            eprintln!(
+                "WARN : pingTimeout must be at least {} seconds, using {} instead of {}",
+                MIN_PING_TIMEOUT, MIN_PING_TIMEOUT, config.ping_timeout
+            );
+            MIN_PING_TIMEOUT
+        } else {
+            config.ping_timeout
+        };
+        Keepalive {
+            interval: (config.ping_interval > 0).then(|| Duration::from_secs(config.ping_interval)),
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+}
+
+/// Last time a WebSocket client was heard from, updated by the reader on every frame, pongs
+/// included.
+pub type LastHeard = Arc<std::sync::Mutex<Instant>>;
+
+/// Sends a close frame, ignoring the clients that are already gone.
+async fn close_websocket(
+    sink: &mut stream::SplitSink<WebSocketStream<Stream>, Message>,
+    code: CloseCode,
+    reason: &'static str,
+) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    match future::timeout(SEND_TIMEOUT, sink.send(Message::Close(Some(frame)))).await {
+        Ok(Ok(_)) | Ok(Err(WsError::ConnectionClosed)) | Err(_) => (),
+        Ok(Err(error)) => eprintln!("Unable to close sink : {}", error),
+    }
+}
+
+/// Sent by the reader of a WebSocket client to its sender task, which owns the connection.
+pub enum Answer {
+    /// Event for this client only, it doesn't have a sequence number
+    Event(String),
+    /// Replaces the queue of the client, e.g. by one starting with a new snapshot
+    Resubscribe(Subscription),
+}
+
+/// Event telling a client its message was refused, `code` is machine-readable.
+pub fn error_event(code: &str, message: &str) -> String {
+    json!({"type": "error", "code": code, "message": message}).to_string()
+}
+
+type EventStream =
+    stream::Chain<stream::Iter<std::vec::IntoIter<Arc<Event>>>, mpsc::Receiver<Arc<Event>>>;
+
+fn event_stream(subscription: Subscription) -> EventStream {
+    stream::iter(subscription.events).chain(subscription.receiver)
+}
+
+/// Sender task of a WebSocket client, it forwards the snapshot then the queue and pings the client
+/// in between. The answers of the reader are sent as soon as possible, ahead of the queue, and
+/// `sending` is cleared once the first events of the latest subscription are sent. When the
+/// queue ends because the client fell behind, the client is asked to reconnect. The task ends when
+/// the client is gone, the connection must then be closed.
+pub async fn forward_to_websocket(
+    mut sink: stream::SplitSink<WebSocketStream<Stream>, Message>,
+    subscription: Subscription,
+    mut answers: mpsc::Receiver<Answer>,
+    sending: Arc<AtomicBool>,
+    keepalive: Keepalive,
+    last_heard: LastHeard,
+    remote: String,
+) {
+    // First events of the subscription left to send, `sending` is cleared once they are all sent
+    let mut left = subscription.events.len();
+    let mut events = event_stream(subscription);
+    let mut next_ping = keepalive.interval.map(|interval| Instant::now() + interval);
+    // Oldest ping the client didn't answer yet
+    let mut ping_sent: Option<Instant> = None;
+    loop {
+        // Checked before waiting for an event, so a busy timeline doesn't delay the pings
+        let now = Instant::now();
+        if let Some(sent) = ping_sent {
+            if *last_heard.lock().unwrap() >= sent {
+                ping_sent = None;
+            } else if now >= sent + keepalive.timeout {
+                // A client that stopped answering rarely gets the close frame, it usually only
+                // notices the connection dropped (1006) once it wakes up
+                eprintln!("{} : No pong received, disconnecting", remote);
+                return close_websocket(&mut sink, CloseCode::Away, "No pong received").await;
+            }
+        }
+        let message = match (next_ping, keepalive.interval) {
+            (Some(ping), Some(interval)) if now >= ping => {
+                next_ping = Some(now + interval);
+                ping_sent = ping_sent.or(Some(now));
+                Message::Ping(Vec::new())
+            }
+            _ => {
+                let deadline = match (next_ping, ping_sent) {
+                    (Some(ping), Some(sent)) => Some(ping.min(sent + keepalive.timeout)),
+                    (ping, _) => ping,
+                };
+                let next = futures::future::select(answers.next(), events.next());
+                let next = match deadline {
+                    Some(deadline) => match future::timeout(deadline - now, next).await {
+                        Ok(next) => next,
+                        Err(_) => continue,
+                    },
+                    None => next.await,
+                };
+                let next = match next {
+                    Either::Left((answer, _)) => Either::Left(answer),
+                    Either::Right((event, _)) => Either::Right(event),
+                };
+                match next {
+                    Either::Left(Some(Answer::Event(json))) => Message::text(json),
+                    Either::Left(Some(Answer::Resubscribe(subscription))) => {
+                        left = subscription.events.len();
+                        events = event_stream(subscription);
+                        continue;
+                    }
+                    // The reader is gone, so is the connection
+                    Either::Left(None) => return,
+                    Either::Right(Some(event)) => {
+                        if left > 0 {
+                            left -= 1;
+                            if left == 0 {
+                                sending.store(false, Ordering::Release);
+                            }
+                        }
+                        Message::text(&event.json)
+                    }
+                    Either::Right(None) => break,
+                }
+            }
+        };
+        match future::timeout(SEND_TIMEOUT, sink.send(message)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(_)) => return,
+            Err(_) => return eprintln!("{} : Not receiving the events, disconnecting", remote),
+        }
+    }
+    eprintln!("{} : Too far behind, asking to reconnect", remote);
+    close_websocket(
+        &mut sink,
+        CloseCode::Again,
+        "Too far behind, reconnect to get the timeline",
+    )
+    .await;
+}
+
+struct PollSession {
+    receiver: mpsc::Receiver<Arc<Event>>,
+    last_poll: Instant,
+}
+
+/// Queues of the long-polling clients, kept between their requests. A session that isn't polled for
+/// a while is forgotten, the broadcast drops its queue on the next event.
+#[derive(Default)]
+pub struct PollSessions {
+    sessions: std::sync::Mutex<HashMap<String, PollSession>>,
+}
+
+impl PollSessions {
+    fn insert(&self, id: String, receiver: mpsc::Receiver<Arc<Event>>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.last_poll.elapsed() < POLL_SESSION_EXPIRY);
+        sessions.insert(
+            id,
+            PollSession {
+                receiver,
+                last_poll: Instant::now(),
+            },
+        );
+    }
+
+    /// Starts a session, returns its id with the first events and whether they are a snapshot.
+    pub async fn create(
+        &self,
+        hub: &Hub,
+        tweeps: &Tweeps,
+        date: &Date,
+        images: &Images,
+        since: Option<u64>,
+    ) -> (String, Vec<Arc<Event>>, bool) {
+        let subscription = hub.subscribe(tweeps, date, images, since).await;
+        let id = pairing::to_hex(&pairing::random_bytes(POLL_SESSION_ID_SIZE));
+        self.insert(id.clone(), subscription.receiver);
+        (id, subscription.events, subscription.snapshot)
+    }
+
+    /// Waits for the next events of the session `id`, an empty list is returned if nothing
+    /// happened in time. `None` if the session doesn't exist or was dropped for being too slow, the
+    /// client must create a new one.
+    pub async fn poll(&self, id: &str) -> Option<Vec<Arc<Event>>> {
+        // The session is taken out while waiting, so a second request for it doesn't steal events
+        let mut session = self.sessions.lock().unwrap().remove(id)?;
+        let mut events = Vec::new();
+        match future::timeout(POLL_TIMEOUT, session.receiver.next()).await {
+            Ok(Some(event)) => events.push(event),
+            Ok(None) => return None,
+            Err(_) => (),
+        }
+        loop {
+            match session.receiver.try_next() {
+                Ok(Some(event)) => events.push(event),
+                Ok(None) => return Some(events),
+                Err(_) => break,
+            }
+        }
+        self.insert(id.to_string(), session.receiver);
+        Some(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hub, Keepalive, Subscription, EVENT_LOG_SIZE, MIN_PING_TIMEOUT};
+    use crate::config::Config;
+
+    use async_std::sync::{Arc, Mutex, RwLock};
+    use async_std::task;
+
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    fn subscribe(hub: &Hub, since: Option<u64>) -> Subscription {
+        let tweeps = Arc::new(Mutex::new(Vec::new()));
+        let date = Arc::new(RwLock::new(0));
+        let images = Arc::new(RwLock::new(Default::default()));
+        task::block_on(hub.subscribe(&tweeps, &date, &images, since))
+    }
+
+    /// Publishes `count` events and returns their sequence numbers.
+    fn publish(hub: &Hub, count: usize) -> Vec<u64> {
+        let mut state = task::block_on(hub.lock());
+        (0..count)
+            .map(|_| {
+                state.publish(json!({"type": "date", "date": 0}));
+                state.last_seq()
+            })
+            .collect()
+    }
+
+    fn seqs(subscription: &Subscription) -> Vec<Option<u64>> {
+        subscription.events.iter().map(|event| event.seq).collect()
+    }
+
+    #[test]
+    fn empty_log() {
+        let hub = Hub::new("{}".to_string());
+        let last_seq = task::block_on(hub.lock()).last_seq();
+        let subscription = subscribe(&hub, Some(last_seq));
+        assert!(!subscription.snapshot);
+        assert_eq!(seqs(&subscription), vec![None]);
+
+        // Numbers of a previous run of the server are older than the log
+        assert!(subscribe(&hub, Some(last_seq - 1)).snapshot);
+        assert!(subscribe(&hub, None).snapshot);
+    }
+
+    #[test]
+    fn replay() {
+        let hub = Hub::new("{}".to_string());
+        let published = publish(&hub, 3);
+        let subscription = subscribe(&hub, Some(published[0]));
+        assert!(!subscription.snapshot);
+        assert_eq!(
+            seqs(&subscription),
+            vec![None, Some(published[1]), Some(published[2])]
+        );
+    }
+
+    #[test]
+    fn gap_beyond_log() {
+        let hub = Hub::new("{}".to_string());
+        let published = publish(&hub, EVENT_LOG_SIZE + 2);
+        assert!(subscribe(&hub, Some(published[0])).snapshot);
+
+        let subscription = subscribe(&hub, Some(published[1]));
+        assert!(!subscription.snapshot);
+        assert_eq!(subscription.events.len(), EVENT_LOG_SIZE + 1);
+        assert_eq!(subscription.events[1].seq, Some(published[2]));
+    }
+
+    #[test]
+    fn since_ahead_of_log() {
+        let hub = Hub::new("{}".to_string());
+        let published = publish(&hub, 2);
+        assert!(subscribe(&hub, Some(published[1] + 1)).snapshot);
+        assert!(subscribe(&hub, Some(u64::MAX)).snapshot);
+    }
+
+    #[test]
+    fn reconnect_during_snapshot() {
+        let hub = Hub::new("{}".to_string());
+        let published = publish(&hub, 2);
+        let subscription = subscribe(&hub, None);
+        assert!(subscription.snapshot);
+        assert_eq!(
+            seqs(&subscription),
+            vec![None, Some(0), None, None, Some(published[1])]
+        );
+
+        // Only the `clear` was received before the connection was lost
+        let subscription = subscribe(&hub, Some(0));
+        assert!(subscription.snapshot);
+        assert_eq!(subscription.events.last().unwrap().seq, Some(published[1]));
+    }
+
+    #[test]
+    fn resubscribe() {
+        let hub = Hub::new("{}".to_string());
+        let published = publish(&hub, 1);
+        let tweeps = Arc::new(Mutex::new(Vec::new()));
+        let date = Arc::new(RwLock::new(0));
+        let images = Arc::new(RwLock::new(Default::default()));
+        let subscription = task::block_on(hub.resubscribe(&tweeps, &date, &images));
+        assert!(subscription.snapshot);
+        assert_eq!(
+            seqs(&subscription),
+            vec![Some(0), None, None, Some(published[0])]
+        );
+    }
+
+    #[test]
+    fn ping_timeout() {
+        let keepalive = |ping_interval, ping_timeout| {
+            Keepalive::new(&Config {
+                ping_interval,
+                ping_timeout,
+                ..Default::default()
+            })
+        };
+        assert_eq!(
+            keepalive(20, 0).timeout,
+            Duration::from_secs(MIN_PING_TIMEOUT)
+        );
+        assert_eq!(keepalive(20, 30).timeout, Duration::from_secs(30));
+        assert_eq!(keepalive(0, 30).interval, None);
+    }
+}
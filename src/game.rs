@@ -3,20 +3,32 @@ use async_std::sync::{Arc, Mutex, RwLock};
 
 use futures::prelude::*;
 
-use async_tungstenite::tungstenite::protocol::Message;
-use tungstenite::error::Error as WsError;
-
 use std::io::{Error as IoError, ErrorKind};
+use std::time::Instant;
 
 use serde::{ser::SerializeMap, Serialize, Serializer};
 use serde_json::json;
 
-enum SC3Op {
+use super::authors::Authors;
+use super::config::Config;
+use super::events::Hub;
+use super::images::{self, Images};
+
+#[derive(Clone)]
+pub enum SC3Op {
     Linebreak(usize),
     RubyBase(usize),
     RubyEnd(usize),
 }
 
+impl SC3Op {
+    pub fn offset(&self) -> usize {
+        match self {
+            SC3Op::Linebreak(o) | SC3Op::RubyBase(o) | SC3Op::RubyEnd(o) => *o,
+        }
+    }
+}
+
 impl Serialize for SC3Op {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         let mut map = serializer.serialize_map(Some(2))?;
@@ -31,10 +43,10 @@ impl Serialize for SC3Op {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct SC3String {
-    content: String,
-    markers: Vec<SC3Op>,
+    pub content: String,
+    pub markers: Vec<SC3Op>,
 }
 
 impl SC3String {
@@ -82,7 +94,7 @@ impl SC3String {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct Tweep {
     pub id: u32,
     pub tab: u8,
@@ -135,28 +147,116 @@ impl Tweep {
 pub type Tweeps = Arc<Mutex<Vec<Tweep>>>;
 pub type Date = Arc<RwLock<u32>>;
 
+/// Reasons a reply chosen by a client can't be sent to the game.
+#[derive(Debug)]
+pub enum ReplyError {
+    UnknownTweep,
+    UnknownReply,
+    Io(IoError),
+}
+
+impl ReplyError {
+    /// Machine-readable name of the error for the clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReplyError::UnknownTweep => "unknown_tweep",
+            ReplyError::UnknownReply => "unknown_reply",
+            ReplyError::Io(_) => "game_unavailable",
+        }
+    }
+}
+
+impl std::fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplyError::UnknownTweep => write!(f, "Invalid tweep id from tweep reply"),
+            ReplyError::UnknownReply => write!(f, "Invalid reply id from tweep reply"),
+            ReplyError::Io(e) => write!(f, "Unable to send the reply to the game : {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+/// Sends the reply `reply_id` of the tweep `tweep_id` to the game once it is known to exist. The
+/// tweeps stay locked while writing so two replies can't be interleaved.
+pub async fn send_reply(tweeps: &Tweeps, tweep_id: u32, reply_id: u32) -> Result<(), ReplyError> {
+    let locked_tweeps = tweeps.lock().await;
+    let tweep = locked_tweeps
+        .iter()
+        .find(|tweep| tweep.id == tweep_id)
+        .ok_or(ReplyError::UnknownTweep)?;
+    if reply_id as usize >= tweep.replies.len() {
+        return Err(ReplyError::UnknownReply);
+    }
+
+    let message = [
+        0x594c5052_u32.to_ne_bytes(),
+        tweep_id.to_ne_bytes(),
+        reply_id.to_ne_bytes(),
+    ]
+    .concat();
+    let mut stdout = io::stdout();
+    stdout.write_all(&message).await.map_err(ReplyError::Io)?;
+    stdout.flush().await.map_err(ReplyError::Io)
+}
+
+/// What the server knows about its connection with the game, for the clients polling the state. The
+/// server stops with the game, so the game is connected as soon as it sent a message.
+#[derive(Default)]
+pub struct GameStatus {
+    pub messages: u64,
+    pub last_message: Option<Instant>,
+}
+
+impl GameStatus {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "connected": self.last_message.is_some(),
+            "messages": self.messages,
+            "last_message_seconds_ago": self.last_message.map(|t| t.elapsed().as_secs()),
+        })
+    }
+}
+
+pub type Status = Arc<RwLock<GameStatus>>;
+
 pub async fn read_stdin(
-    write_streams: super::http::WriteStreams,
+    hub: Arc<Hub>,
     tweeps: Tweeps,
     date: Date,
+    authors: Authors,
+    images: Images,
+    config: Arc<Config>,
+    status: Status,
 ) -> Result<(), IoError> {
     let mut stdin = io::stdin();
 
     loop {
         let mut message_type_buf = [0u8; 4];
         stdin.read_exact(&mut message_type_buf).await?;
-        let next_message = match u32::from_ne_bytes(message_type_buf) {
+        {
+            let mut locked_status = status.write().await;
+            locked_status.messages += 1;
+            locked_status.last_message = Some(Instant::now());
+        }
+        // The hub is locked before changing the timeline, see `Hub::lock`
+        let (mut locked_hub, next_message) = match u32::from_ne_bytes(message_type_buf) {
             0x434c4541 => {
                 // "CLEA" : Clear
+                let locked_hub = hub.lock().await;
+                authors.lock().await.clear_tweeps();
                 tweeps.lock().await.clear();
-                json!({"type": "clear"}).to_string()
+                (locked_hub, json!({"type": "clear"}))
             }
             0x54574550 => {
                 // "TWEP" : Tweep
                 let tweep = Tweep::read_from_stdin(&mut stdin).await?;
-                let tweep_as_json = json!({"type": "tweep", "tweep": tweep}).to_string();
+                let tweep_as_json = json!({"type": "tweep", "tweep": tweep});
+                let locked_hub = hub.lock().await;
+                authors.lock().await.add_tweep(&tweep);
                 tweeps.lock().await.push(tweep);
-                tweep_as_json
+                (locked_hub, tweep_as_json)
             }
             0x53545250 => {
                 // "STRP" : Set Reply Possible
@@ -167,6 +267,7 @@ pub async fn read_stdin(
                 stdin.read_exact(&mut possible_buf).await?;
                 let possible = u16::from_ne_bytes(possible_buf) != 0;
 
+                let locked_hub = hub.lock().await;
                 let mut locked_tweeps = tweeps.lock().await;
                 let tweep = match locked_tweeps.iter_mut().find(|tweep| tweep.id == id) {
                     Some(t) => t,
@@ -176,12 +277,14 @@ pub async fn read_stdin(
                 };
                 tweep.reply_possible = possible;
 
-                json!({
-                       "type": "set_reply_possible",
-                       "tweep_id": id,
-                       "possible": possible,
-                })
-                .to_string()
+                (
+                    locked_hub,
+                    json!({
+                           "type": "set_reply_possible",
+                           "tweep_id": id,
+                           "possible": possible,
+                    }),
+                )
             }
             0x44415445 => {
                 // "DATE": New date
@@ -189,26 +292,42 @@ pub async fn read_stdin(
                 stdin.read_exact(&mut new_date_buf).await?;
                 let new_date = u32::from_ne_bytes(new_date_buf);
 
+                let locked_hub = hub.lock().await;
                 *(date.write().await) = new_date;
-                json!({"type": "date", "date": new_date}).to_string()
+                (locked_hub, json!({"type": "date", "date": new_date}))
+            }
+            0x54455852 => {
+                // "TEXR" : Texture replacement
+                let mut name_size_buf = [0u8; 4];
+                stdin.read_exact(&mut name_size_buf).await?;
+                let mut name_buf = vec![0u8; u32::from_ne_bytes(name_size_buf) as usize];
+                stdin.read_exact(&mut name_buf).await?;
+                let mut size_buf = [0u8; 4];
+                stdin.read_exact(&mut size_buf).await?;
+                let mut data = vec![0u8; u32::from_ne_bytes(size_buf) as usize];
+                stdin.read_exact(&mut data).await?;
+
+                let name = String::from_utf8_lossy(&name_buf);
+                let urls = match images::add_texture(&images, &config, &name, data).await {
+                    Ok(u) => u,
+                    // The whole message was consumed, a texture we can't use doesn't desync us
+                    Err(e) => {
+                        eprintln!("Unable to load texture {} from game : {}", name, e);
+                        continue;
+                    }
+                };
+                eprintln!("Texture {} loaded from game", name);
+
+                (
+                    hub.lock().await,
+                    json!({"type": "reload_images", "urls": urls}),
+                )
             }
             _ => {
                 panic!("Unknown message type from game : possible desync !");
             }
         };
 
-        let mut index_to_remove: Vec<usize> = Vec::new();
-        let mut locked_write_streams = write_streams.lock().await;
-        for (index, stream) in locked_write_streams.iter_mut().enumerate() {
-            if stream.send(Message::text(&next_message)).await.is_err() {
-                index_to_remove.push(index);
-            }
-        }
-        for index in index_to_remove.iter().rev() {
-            match locked_write_streams.remove(*index).close().await {
-                Ok(_) | Err(WsError::ConnectionClosed) => (),
-                Err(error) => eprintln!("Unable to close sink : {}", error),
-            }
-        }
+        locked_hub.publish(next_message);
     }
 }
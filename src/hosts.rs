@@ -0,0 +1,202 @@
+use std::net::{IpAddr, SocketAddr};
+
+use super::config::Config;
+use super::mdns;
+
+/// Hosts the web client may be loaded from. A browser tricked into sending requests to the server
+/// by another website (DNS rebinding or cross-site WebSocket) sends a `Host` or `Origin` that isn't
+/// one of them.
+pub struct AllowedHosts {
+    any: bool,
+    // A missing port matches every port, it is only used for the entries of the configuration
+    hosts: Vec<(String, Option<u16>)>,
+    // Port of the scheme, for a `Host` header without one
+    default_port: u16,
+}
+
+/// Splits `host[:port]` in a lowercase host and its port, IPv6 addresses keep their brackets.
+fn split_host_port(authority: &str) -> Option<(String, Option<u16>)> {
+    let authority = authority.trim().to_lowercase();
+    let (host, port) = if authority.starts_with('[') {
+        let end = authority.find(']')?;
+        let port = match &authority[end + 1..] {
+            "" => None,
+            port => Some(port.strip_prefix(':')?),
+        };
+        (authority[..=end].to_string(), port)
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) => (host.to_string(), Some(port)),
+            None => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(port) => Some(port.parse::<u16>().ok()?),
+        None => None,
+    };
+    (!host.is_empty()).then_some((host, port))
+}
+
+/// Host names and IP addresses the server can be reached with from `listen_addresses`.
+pub fn server_names(listen_addresses: &[SocketAddr], config: &Config) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    if config.mdns {
+        names.push(mdns::hostname(config));
+    }
+    for listen_address in listen_addresses {
+        let ip = listen_address.ip();
+        if ip.is_unspecified() {
+            match local_ip_address::list_afinet_netifas() {
+                Ok(addrs) => names.extend(addrs.iter().map(|(_, addr)| addr.to_string())),
+                Err(e) => eprintln!("Unable to get your local IP addresses : {}", e),
+            }
+        } else {
+            names.push(ip.to_string());
+        }
+        if ip.is_unspecified() || ip.is_loopback() {
+            for loopback in ["localhost", "127.0.0.1", "::1"] {
+                names.push(loopback.to_string());
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// IPv6 addresses are written between brackets in `Host` and `Origin`.
+fn host_name(name: &str) -> String {
+    match name.parse::<IpAddr>() {
+        Ok(IpAddr::V6(v6)) => format!("[{}]", v6),
+        _ => name.to_string(),
+    }
+}
+
+impl AllowedHosts {
+    pub fn new(listen_addresses: &[SocketAddr], config: &Config, tls: bool) -> AllowedHosts {
+        let mut hosts: Vec<(String, Option<u16>)> = Vec::new();
+        for listen_address in listen_addresses {
+            let port = Some(listen_address.port());
+            hosts.extend(
+                server_names(&[*listen_address], config)
+                    .iter()
+                    .map(|name| (host_name(name), port)),
+            );
+        }
+
+        let mut any = false;
+        for host in config.allowed_hosts.iter() {
+            if host == "*" {
+                any = true;
+            } else if let Some(host) = split_host_port(host) {
+                hosts.push(host);
+            } else {
+                eprintln!("WARN : Ignoring invalid allowed host {:?}", host);
+            }
+        }
+
+        AllowedHosts {
+            any,
+            hosts,
+            default_port: if tls { 443 } else { 80 },
+        }
+    }
+
+    fn is_allowed(&self, host: &str, port: u16) -> bool {
+        self.any
+            || self.hosts.iter().any(|(allowed_host, allowed_port)| {
+                allowed_host == host && allowed_port.is_none_or(|p| p == port)
+            })
+    }
+
+    /// Checks the value of the `Host` header, without a port the default one of HTTP or HTTPS is
+    /// assumed.
+    pub fn check_host(&self, host: &str) -> bool {
+        match split_host_port(host) {
+            Some((host, port)) => self.is_allowed(&host, port.unwrap_or(self.default_port)),
+            None => self.any,
+        }
+    }
+
+    /// Checks the value of the `Origin` header, opaque origins (`null`) are never allowed.
+    pub fn check_origin(&self, origin: &str) -> bool {
+        let (default_port, authority) = match origin.split_once("://") {
+            Some((scheme, authority)) if scheme.eq_ignore_ascii_case("http") => (80, authority),
+            Some((scheme, authority)) if scheme.eq_ignore_ascii_case("https") => (443, authority),
+            _ => return self.any,
+        };
+        match split_host_port(authority) {
+            Some((host, port)) => self.is_allowed(&host, port.unwrap_or(default_port)),
+            None => self.any,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_host_port as split, AllowedHosts};
+    use crate::config::Config;
+
+    use std::net::SocketAddr;
+
+    fn allowed_hosts(listen_address: &str, tls: bool) -> AllowedHosts {
+        let listen_address: SocketAddr = listen_address.parse().unwrap();
+        let config = Config {
+            mdns: false,
+            ..Default::default()
+        };
+        AllowedHosts::new(&[listen_address], &config, tls)
+    }
+
+    #[test]
+    fn split_names() {
+        assert_eq!(
+            split("Twipo.Local"),
+            Some(("twipo.local".to_string(), None))
+        );
+        assert_eq!(
+            split(" my-pc.lan:8080 "),
+            Some(("my-pc.lan".to_string(), Some(8080)))
+        );
+        assert_eq!(
+            split("192.168.1.2:80"),
+            Some(("192.168.1.2".to_string(), Some(80)))
+        );
+    }
+
+    #[test]
+    fn split_ipv6() {
+        assert_eq!(split("[::1]"), Some(("[::1]".to_string(), None)));
+        assert_eq!(
+            split("[FE80::1]:8080"),
+            Some(("[fe80::1]".to_string(), Some(8080)))
+        );
+        assert_eq!(split("[::1]8080"), None);
+        assert_eq!(split("[::1"), None);
+    }
+
+    #[test]
+    fn split_invalid() {
+        assert_eq!(split(""), None);
+        assert_eq!(split(":8080"), None);
+        assert_eq!(split("host:"), None);
+        assert_eq!(split("host:99999"), None);
+        assert_eq!(split("host:port"), None);
+    }
+
+    #[test]
+    fn default_ports() {
+        let https = allowed_hosts("127.0.0.1:443", true);
+        assert!(https.check_host("localhost"));
+        assert!(https.check_host("127.0.0.1"));
+        assert!(https.check_host("[::1]:443"));
+        assert!(!https.check_host("localhost:80"));
+        assert!(https.check_origin("https://localhost"));
+        assert!(!https.check_origin("http://localhost"));
+
+        let http = allowed_hosts("127.0.0.1:80", false);
+        assert!(http.check_host("localhost"));
+        assert!(!http.check_host("localhost:443"));
+        assert!(!http.check_host("example.com"));
+    }
+}
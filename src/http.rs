@@ -1,22 +1,41 @@
 use async_std::io;
-use async_std::net::{SocketAddr, TcpListener, TcpStream};
-use async_std::sync::{Arc, Mutex};
+use async_std::net::{IpAddr, SocketAddr, TcpListener};
+use async_std::sync::Arc;
 use async_std::task;
 
+use futures::channel::mpsc;
 use futures::prelude::*;
 
+use std::borrow::Cow;
 use std::error::Error;
 use std::io::{Error as IoError, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
+use async_tungstenite::tungstenite::protocol::Message;
+
+use base64::Engine;
 use sha1::{Digest, Sha1};
 
-use async_tungstenite::tungstenite::protocol::Message;
+use futures_rustls::TlsAcceptor;
 
 use serde::Deserialize;
 use serde_json::json;
 
-use super::game::{Date, Tweeps};
-use super::images::ImageList;
+use super::authors::Authors;
+use super::card;
+use super::encoding::{Encoding, MINIMUM_COMPRESSED_SIZE};
+use super::events::{self, Answer, Event, Hub, Keepalive, LastHeard, PollSessions};
+use super::game::{self, Date, ReplyError, Status, Tweep, Tweeps};
+use super::hosts::AllowedHosts;
+use super::images::Images;
+use super::pairing::{Access, Pairing, PairingError, SESSION_COOKIE};
+use super::proxy::ReverseProxy;
+use super::qr;
+use super::router::{self, Endpoint, Method, Query, RoutingResult};
+use super::theme::Theme;
+use super::tls::Stream;
 
 struct HttpError {
     code: u32,
@@ -31,168 +50,685 @@ const HTTP_400: HttpError = HttpError {
     code: 400,
     status: "Bad Request",
 };
+const HTTP_408: HttpError = HttpError {
+    code: 408,
+    status: "Request Timeout",
+};
+const HTTP_413: HttpError = HttpError {
+    code: 413,
+    status: "Payload Too Large",
+};
+const HTTP_429: HttpError = HttpError {
+    code: 429,
+    status: "Too Many Requests",
+};
+const HTTP_431: HttpError = HttpError {
+    code: 431,
+    status: "Request Header Fields Too Large",
+};
+const HTTP_403: HttpError = HttpError {
+    code: 403,
+    status: "Forbidden",
+};
+const HTTP_405: HttpError = HttpError {
+    code: 405,
+    status: "Method Not Allowed",
+};
+const HTTP_422: HttpError = HttpError {
+    code: 422,
+    status: "Unprocessable Content",
+};
+const HTTP_500: HttpError = HttpError {
+    code: 500,
+    status: "Internal Server Error",
+};
+const HTTP_501: HttpError = HttpError {
+    code: 501,
+    status: "Not Implemented",
+};
+const HTTP_503: HttpError = HttpError {
+    code: 503,
+    status: "Service Unavailable",
+};
+
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(15);
+// Counted from the first byte of a request, a client sending it slowly can't keep the connection
+// open longer than that
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEADERS: usize = 32;
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 64 * 1024;
+// Every phone of a LAN party should fit, WebSockets included
+const MAX_CONNECTIONS: usize = 128;
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REJECT_LINGER: Duration = Duration::from_millis(100);
+
+// Comments are sent on idle event streams so proxies keep them open and dead clients are noticed
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+const EVENTS_RETRY_MS: u32 = 1000;
+// Answers to the messages of a WebSocket client waiting to be sent, the reader waits when it is full
+const ANSWER_QUEUE_SIZE: usize = 8;
+
+const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const CACHE_REVALIDATE: &str = "no-cache";
 
-pub type WriteStreams =
-    Arc<Mutex<Vec<stream::SplitSink<async_tungstenite::WebSocketStream<TcpStream>, Message>>>>;
+/// Resource of the web client embedded in the executable, it is copied with its compressed versions
+/// by `build.rs`.
+struct EmbeddedResource {
+    content: Cow<'static, [u8]>,
+    gzip: Cow<'static, [u8]>,
+    brotli: Cow<'static, [u8]>,
+    content_type: &'static str,
+    hash: OnceLock<String>,
+}
+
+macro_rules! embedded_resource {
+    ($name:literal, $content_type:literal) => {
+        EmbeddedResource {
+            content: Cow::Borrowed(include_bytes!(concat!(env!("OUT_DIR"), "/", $name))),
+            gzip: Cow::Borrowed(include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".gz"))),
+            brotli: Cow::Borrowed(include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".br"))),
+            content_type: $content_type,
+            hash: OnceLock::new(),
+        }
+    };
+}
+
+impl EmbeddedResource {
+    /// Resource generated at runtime, compressed once when it is created.
+    fn generated(
+        content: Vec<u8>,
+        content_type: &'static str,
+    ) -> Result<EmbeddedResource, IoError> {
+        Ok(EmbeddedResource {
+            gzip: Cow::Owned(Encoding::Gzip.compress(&content)?),
+            brotli: Cow::Owned(Encoding::Brotli.compress(&content)?),
+            content: Cow::Owned(content),
+            content_type,
+            hash: OnceLock::new(),
+        })
+    }
+
+    fn encoded(&self, encoding: Encoding) -> &[u8] {
+        match encoding {
+            Encoding::Identity => &self.content,
+            Encoding::Gzip => &self.gzip,
+            Encoding::Brotli => &self.brotli,
+        }
+    }
+
+    /// Each encoding is a different representation, so they can't share the same strong ETag.
+    fn etag(&self, encoding: Encoding) -> String {
+        let hash = self.hash.get_or_init(|| content_hash(&self.content));
+        match encoding.name() {
+            Some(name) => format!("\"{}-{}\"", hash, name),
+            None => format!("\"{}\"", hash),
+        }
+    }
+}
+
+static INDEX_HTML: EmbeddedResource = embedded_resource!("index.html", "text/html; charset=utf-8");
+// `index.html` with the base path in its `<base>` element, only used when the base path is set
+static BASE_INDEX_HTML: OnceLock<Result<EmbeddedResource, String>> = OnceLock::new();
+const INDEX_HTML_BASE: &str = "<base href=\"./\"/>";
+static INDEX_JS: EmbeddedResource =
+    embedded_resource!("index.js", "text/javascript; charset=utf-8");
+
+/// Hash of the embedded web client, written in `index.html` by the build script so a page whose
+/// client is older than the server can tell.
+pub const CLIENT_HASH: &str = env!("TWIPO_CLIENT_HASH");
+
+/// Short hexadecimal hash of `data`, used for the ETags and the versioned URLs of the images.
+pub fn content_hash(data: &[u8]) -> String {
+    Sha1::digest(data)[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// `If-None-Match` uses the weak comparison, so the `W/` prefixes are ignored.
+fn is_not_modified(headers: &[httparse::Header], etag: &str) -> bool {
+    match find_header(headers, "If-None-Match") {
+        Some(value) => value
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag),
+        None => false,
+    }
+}
+
+pub fn find_header<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .and_then(|header| std::str::from_utf8(header.value).ok())
+}
+
+fn cookie<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a str> {
+    find_header(headers, "Cookie")?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// The theme can be selected with the `theme` query parameter, which is remembered in a cookie so
+/// the following requests (e.g. the stylesheet) don't need it.
+fn requested_theme(query: &Query, headers: &[httparse::Header]) -> &'static Theme {
+    query
+        .get("theme")
+        .or_else(|| cookie(headers, "theme"))
+        .and_then(Theme::from_name)
+        .unwrap_or_else(Theme::default_theme)
+}
+
+/// Body of a `/api/poll` response, the events are already serialized.
+fn poll_response(session: &str, snapshot: bool, events: &[Arc<Event>]) -> String {
+    let events: Vec<&str> = events.iter().map(|event| event.json.as_str()).collect();
+    format!(
+        "{{\"session\":{},\"snapshot\":{},\"events\":[{}]}}",
+        json!(session),
+        snapshot,
+        events.join(",")
+    )
+}
+
+/// Sequence number of the last event a reconnecting client received, from the `since` query
+/// parameter or the `Last-Event-ID` header sent by `EventSource`. The header wins since the browser
+/// reconnects with the URL of the first connection. An invalid one is ignored, the client then
+/// gets a snapshot.
+fn requested_since(query: &Query, headers: &[httparse::Header]) -> Option<u64> {
+    find_header(headers, "Last-Event-ID")
+        .or_else(|| query.get("since"))
+        .and_then(|since| since.parse().ok())
+}
+
+/// An event with its sequence number as the id, so `EventSource` reconnects from it.
+fn server_sent_event(event: &Event) -> String {
+    match event.seq {
+        Some(seq) => format!("id: {}\ndata: {}\n\n", seq, event.json),
+        None => format!("data: {}\n\n", event.json),
+    }
+}
+
+/// Filters of `/api/tweeps`, every one given in the query must match.
+#[derive(Default)]
+struct TweepFilter {
+    tab: Option<u8>,
+    author: Option<String>,
+    date: Option<u32>,
+    reply_possible: Option<bool>,
+}
+
+impl TweepFilter {
+    /// `None` if one of the filters has an invalid value.
+    fn from_query(query: &Query) -> Option<TweepFilter> {
+        fn parse<T: std::str::FromStr>(value: Option<&str>) -> Option<Option<T>> {
+            match value {
+                Some(value) => value.parse().ok().map(Some),
+                None => Some(None),
+            }
+        }
+        Some(TweepFilter {
+            tab: parse(query.get("tab"))?,
+            author: query.get("author").map(|author| author.to_string()),
+            date: parse(query.get("date"))?,
+            reply_possible: parse(query.get("reply_possible"))?,
+        })
+    }
+
+    fn matches(&self, tweep: &Tweep) -> bool {
+        self.tab.is_none_or(|tab| tweep.tab == tab)
+            && self
+                .author
+                .as_ref()
+                .is_none_or(|author| tweep.author_username.content == *author)
+            && self.date.is_none_or(|date| tweep.post_date == date)
+            && self
+                .reply_possible
+                .is_none_or(|possible| tweep.reply_possible == possible)
+    }
+}
+
+/// The session cookie is never sent back over plain HTTP if it was received over HTTPS.
+fn session_cookie(session: &str, base_path: &str, secure: bool) -> String {
+    format!(
+        "{}={}; Path={}/; Max-Age=31536000; HttpOnly; SameSite=Strict{}",
+        SESSION_COOKIE,
+        session,
+        base_path,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+/// Length of the body announced by `headers`. Chunked bodies aren't supported, and a request whose
+/// length is ambiguous is refused, a proxy could see a different request in it.
+fn body_len(headers: &[httparse::Header]) -> Result<usize, &'static HttpError> {
+    if find_header(headers, "Transfer-Encoding").is_some() {
+        return Err(&HTTP_501);
+    }
+    let mut lengths = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Content-Length"));
+    match (lengths.next(), lengths.next()) {
+        (None, _) => Ok(0),
+        (Some(length), None) => std::str::from_utf8(length.value)
+            .ok()
+            .map(str::trim)
+            .filter(|length| !length.is_empty() && length.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|length| length.parse().ok())
+            .ok_or(&HTTP_400),
+        (Some(_), Some(_)) => Err(&HTTP_400),
+    }
+}
+
+/// Returns the size of the first request of the buffer, including its body, once it was entirely
+/// received. The limits are enforced here so the buffer never grows past them.
+fn complete_request_len(buffer: &[u8]) -> Result<Option<usize>, &'static HttpError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(buffer) {
+        Ok(httparse::Status::Complete(header_len)) => {
+            let body_len = body_len(request.headers)?;
+            if body_len > MAX_BODY_SIZE {
+                Err(&HTTP_413)
+            } else if buffer.len() >= header_len + body_len {
+                Ok(Some(header_len + body_len))
+            } else {
+                Ok(None)
+            }
+        }
+        Ok(httparse::Status::Partial) if buffer.len() > MAX_HEADER_SIZE => Err(&HTTP_431),
+        Ok(httparse::Status::Partial) => Ok(None),
+        Err(httparse::Error::TooManyHeaders) => Err(&HTTP_431),
+        Err(_) => Err(&HTTP_400),
+    }
+}
+
+/// HTTP/1.1 connections are persistent unless the client asks otherwise, HTTP/1.0 ones must opt in.
+fn wants_keep_alive(request: &httparse::Request) -> bool {
+    let connection = find_header(request.headers, "Connection")
+        .unwrap_or("")
+        .to_lowercase();
+    let has_token = |token: &str| connection.split(',').any(|t| t.trim() == token);
+    match request.version {
+        Some(1) => !has_token("close"),
+        _ => has_token("keep-alive"),
+    }
+}
+
+/// State shared by every connection, cloning it only clones the `Arc`s.
+#[derive(Clone)]
+pub struct ServerState {
+    pub hub: Arc<Hub>,
+    pub poll_sessions: Arc<PollSessions>,
+    pub keepalive: Keepalive,
+    pub tweeps: Tweeps,
+    pub date: Date,
+    pub game_status: Status,
+    pub authors: Authors,
+    pub image_list: Images,
+    pub allowed_hosts: Arc<AllowedHosts>,
+    pub pairing: Arc<Pairing>,
+    pub proxy: Arc<ReverseProxy>,
+    pub tls: Option<TlsAcceptor>,
+}
 
 struct HttpConnection {
-    stream: TcpStream,
+    stream: Stream,
+    local_addr: SocketAddr,
     peer_addr: SocketAddr,
-    write_streams: WriteStreams,
-    tweeps: Tweeps,
-    date: Date,
-    image_list: Arc<ImageList>,
+    // The client behind the peer when it is a trusted proxy, the peer itself otherwise
+    client_ip: IpAddr,
+    // The client used HTTPS, possibly to reach a proxy
+    secure: bool,
+    state: ServerState,
+    keep_alive: bool,
+    // Responses to `HEAD` requests have the same headers as `GET` but no body
+    head_request: bool,
+    access: Access,
+    // Last event received by a WebSocket client reconnecting, kept until the upgrade is done
+    since: Option<u64>,
 }
 
 impl HttpConnection {
     fn new(
-        stream: TcpStream,
+        stream: Stream,
         peer_addr: SocketAddr,
-        write_streams: WriteStreams,
-        tweeps: Tweeps,
-        date: Date,
-        image_list: Arc<ImageList>,
+        local_addr: SocketAddr,
+        state: ServerState,
     ) -> HttpConnection {
         HttpConnection {
+            secure: stream.is_tls(),
             stream,
+            local_addr,
             peer_addr,
-            write_streams,
-            tweeps,
-            date,
-            image_list,
+            client_ip: peer_addr.ip(),
+            state,
+            keep_alive: false,
+            head_request: false,
+            access: Access::Denied,
+            since: None,
+        }
+    }
+
+    /// Client shown in the logs, along with the proxy it went through.
+    fn remote(&self) -> String {
+        if self.client_ip == self.peer_addr.ip() {
+            self.peer_addr.to_string()
+        } else {
+            format!("{} via {}", self.client_ip, self.peer_addr)
+        }
+    }
+
+    /// `index.html` with the base path set, so the relative URLs of the web client work whatever the
+    /// URL of the page is.
+    fn index_html(&self) -> Result<&'static EmbeddedResource, &'static str> {
+        let base_path = self.state.proxy.base_path();
+        if base_path.is_empty() {
+            return Ok(&INDEX_HTML);
         }
+        BASE_INDEX_HTML
+            .get_or_init(|| {
+                let html = String::from_utf8_lossy(&INDEX_HTML.content)
+                    .replace(INDEX_HTML_BASE, &format!("<base href=\"{}/\"/>", base_path));
+                EmbeddedResource::generated(html.into_bytes(), INDEX_HTML.content_type)
+                    .map_err(|e| e.to_string())
+            })
+            .as_ref()
+            .map_err(|e| e.as_str())
     }
 
-    async fn handle_websocket(self) -> Result<(), Box<dyn Error>> {
-        eprintln!("{} : WS Opened", self.peer_addr);
-        let ws_stream = async_tungstenite::WebSocketStream::from_raw_socket(
+    /// `leftover` holds the bytes received after the upgrade request, they are already part of the
+    /// WebSocket stream.
+    async fn handle_websocket(self, leftover: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let remote = self.remote();
+        eprintln!("{} : WS Opened", remote);
+        let ws_stream = async_tungstenite::WebSocketStream::from_partially_read(
             self.stream,
+            leftover,
             tungstenite::protocol::Role::Server,
             None,
         )
         .await;
-        let (mut write, mut read) = ws_stream.split();
+        let (write, read) = ws_stream.split();
 
-        let date_as_json = json!({
-            "type": "date",
-            "date": *self.date.read().await,
-        })
-        .to_string();
-        write.send(Message::text(date_as_json)).await?;
-        for tweep in self.tweeps.lock().await.iter() {
-            let tweep_as_json = json!({"type": "tweep", "tweep": tweep}).to_string();
-            write.send(Message::text(tweep_as_json)).await?;
-        }
-        self.write_streams.lock().await.push(write);
-
-        while let Some(message) = read.next().await {
-            let valid_message = message?;
-            let message_str = valid_message.to_text()?;
-            eprintln!("{} : {}", self.peer_addr, message_str.trim());
-
-            #[derive(Deserialize)]
-            struct TweepReply {
-                r#type: String,
-                tweep_id: u32,
-                reply_id: u32,
-            }
-            // I saw Firefox Focus on Android send a "PING" text message instead of a real ping
-            // message, causing the connection to be dropped. To prevent this kind of stupid
-            // disconnections we will just ignore invalid JSONs.
-            // This doesn't make any sense and I was not able to reproduce it but I swear I saw it,
-            // I'm not crazy.
-            let tweep_reply: TweepReply = match serde_json::from_str(message_str) {
-                Ok(j) => j,
-                Err(e) => {
-                    eprintln!("{} : {}", self.peer_addr, e);
-                    continue;
-                }
-            };
-
-            if tweep_reply.r#type != "reply" {
-                return Err(Box::new(IoError::new(
-                    ErrorKind::InvalidData,
-                    "Invalid message type from websocket",
-                )));
+        let subscription = self
+            .state
+            .hub
+            .subscribe(
+                &self.state.tweeps,
+                &self.state.date,
+                &self.state.image_list,
+                self.since,
+            )
+            .await;
+        let last_heard: LastHeard = Arc::new(std::sync::Mutex::new(Instant::now()));
+        let (answers, answers_receiver) = mpsc::channel(ANSWER_QUEUE_SIZE);
+        // Set until the sender has sent the first events of the latest subscription, a client can't
+        // ask for a new snapshot before it got the previous one
+        let snapshot_sending = Arc::new(AtomicBool::new(true));
+        let sender = task::spawn(events::forward_to_websocket(
+            write,
+            subscription,
+            answers_receiver,
+            snapshot_sending.clone(),
+            self.state.keepalive,
+            last_heard.clone(),
+            remote.clone(),
+        ));
+        let reading = Box::pin(
+            read_websocket(
+                &self.state,
+                self.access,
+                read,
+                answers,
+                &snapshot_sending,
+                &last_heard,
+                &remote,
+            )
+            .map(|result| result.map_err(|e| e.to_string())),
+        );
+        let result = match future::select(reading, sender).await {
+            // Dropping the handle would leave the task waiting for the next event
+            future::Either::Left((result, sender)) => {
+                sender.cancel().await;
+                result
             }
-            let locked_tweeps = self.tweeps.lock().await;
-            let tweep = match locked_tweeps
-                .iter()
-                .find(|tweep| tweep.id == tweep_reply.tweep_id)
-            {
-                Some(t) => t,
-                None => {
-                    return Err(Box::new(IoError::new(
-                        ErrorKind::InvalidData,
-                        "Invalid tweep id from tweep reply via websocket",
-                    )))
-                }
-            };
-            if tweep_reply.reply_id as usize >= tweep.replies.len() {
-                return Err(Box::new(IoError::new(
-                    ErrorKind::InvalidData,
-                    "Invalid reply id from tweep reply via websocket",
-                )));
-            }
-
-            let message = [
-                0x594c5052_u32.to_ne_bytes(),
-                tweep_reply.tweep_id.to_ne_bytes(),
-                tweep_reply.reply_id.to_ne_bytes(),
-            ]
-            .concat();
-            io::stdout().write_all(&message).await?;
-            io::stdout().flush().await?;
-        }
-        eprintln!("{} : WS Closed", self.peer_addr);
-        Ok(())
+            // The sender gave up on the client, dropping the reader closes the connection
+            future::Either::Right(((), _)) => Ok(()),
+        };
+        eprintln!("{} : WS Closed", remote);
+        Ok(result?)
     }
 
-    async fn write_response(
+    async fn write_response_with_headers(
         &mut self,
         code: u32,
         status: &str,
         content_type: &str,
+        extra_headers: &[(&str, &str)],
         data: &[u8],
     ) -> Result<(), IoError> {
-        let header = format!(
-            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        let mut header = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n",
             code,
             status,
             content_type,
-            data.len()
+            data.len(),
+            if self.keep_alive {
+                "keep-alive"
+            } else {
+                "close"
+            }
         );
-        self.stream.write_all(header.as_bytes()).await?;
-        self.stream.write_all(data).await?;
-        Ok(())
+        for (name, value) in extra_headers.iter() {
+            header += &format!("{}: {}\r\n", name, value);
+        }
+        header += "\r\n";
+        let data = if self.head_request { &[][..] } else { data };
+        self.send(&[header.as_bytes(), data]).await
+    }
+
+    /// Writes `parts` to the client. A client that doesn't read them in time is given up on, it
+    /// would otherwise keep its connection slot forever by pipelining requests without reading the
+    /// responses.
+    async fn send(&mut self, parts: &[&[u8]]) -> Result<(), IoError> {
+        io::timeout(events::SEND_TIMEOUT, async {
+            for part in parts {
+                self.stream.write_all(part).await?;
+            }
+            // TLS streams buffer what is written until they are flushed
+            self.stream.flush().await
+        })
+        .await
+        .map_err(|e| match e.kind() {
+            ErrorKind::TimedOut => IoError::new(e.kind(), "Not reading the responses"),
+            _ => e,
+        })
+    }
+
+    /// Writes `data` with its `etag`, or a `304 Not Modified` response without any body if the
+    /// client already has it in cache. Returns the status code sent.
+    async fn write_cached_response(
+        &mut self,
+        request_headers: &[httparse::Header<'_>],
+        content_type: &str,
+        cache_control: &str,
+        etag: &str,
+        extra_headers: &[(&str, &str)],
+        data: &[u8],
+    ) -> Result<u32, IoError> {
+        let mut response_headers = vec![("ETag", etag), ("Cache-Control", cache_control)];
+        response_headers.extend_from_slice(extra_headers);
+
+        if is_not_modified(request_headers, etag) {
+            let mut header = format!(
+                "HTTP/1.1 304 Not Modified\r\nConnection: {}\r\n",
+                if self.keep_alive {
+                    "keep-alive"
+                } else {
+                    "close"
+                }
+            );
+            for (name, value) in response_headers.iter() {
+                header += &format!("{}: {}\r\n", name, value);
+            }
+            header += "\r\n";
+            self.send(&[header.as_bytes()]).await?;
+            Ok(304)
+        } else {
+            self.write_response_with_headers(200, "OK", content_type, &response_headers, data)
+                .await?;
+            Ok(200)
+        }
     }
 
-    async fn write_text_response(
+    async fn write_embedded_resource(
+        &mut self,
+        request_headers: &[httparse::Header<'_>],
+        resource: &EmbeddedResource,
+        extra_headers: &[(&str, &str)],
+    ) -> Result<u32, IoError> {
+        let encoding = Encoding::negotiate(find_header(request_headers, "Accept-Encoding"));
+        let mut response_headers = vec![("Vary", "Accept-Encoding")];
+        if let Some(name) = encoding.name() {
+            response_headers.push(("Content-Encoding", name));
+        }
+        response_headers.extend_from_slice(extra_headers);
+        self.write_cached_response(
+            request_headers,
+            resource.content_type,
+            CACHE_REVALIDATE,
+            &resource.etag(encoding),
+            &response_headers,
+            resource.encoded(encoding),
+        )
+        .await
+    }
+
+    async fn write_json_response(
+        &mut self,
+        request_headers: &[httparse::Header<'_>],
+        json: &str,
+    ) -> Result<(), IoError> {
+        self.write_json_response_with_status(200, "OK", request_headers, json)
+            .await
+    }
+
+    /// JSON bodies are generated for each request, so they are compressed on the fly.
+    async fn write_json_response_with_status(
         &mut self,
         code: u32,
         status: &str,
-        content_type: &str,
-        text: &str,
+        request_headers: &[httparse::Header<'_>],
+        json: &str,
     ) -> Result<(), IoError> {
-        self.write_response(
+        let encoding = if json.len() >= MINIMUM_COMPRESSED_SIZE {
+            Encoding::negotiate(find_header(request_headers, "Accept-Encoding"))
+        } else {
+            Encoding::Identity
+        };
+        let mut response_headers = vec![("Vary", "Accept-Encoding")];
+        if let Some(name) = encoding.name() {
+            response_headers.push(("Content-Encoding", name));
+        }
+        self.write_response_with_headers(
             code,
             status,
-            &(content_type.to_owned() + "; charset=utf-8"),
-            text.as_bytes(),
+            "application/json; charset=utf-8",
+            &response_headers,
+            &encoding.compress(json.as_bytes())?,
         )
         .await
     }
 
+    /// Errors of the JSON API have a machine-readable `code` along with the HTTP status.
+    async fn write_json_error(
+        &mut self,
+        request_headers: &[httparse::Header<'_>],
+        error: &HttpError,
+        code: &str,
+    ) -> Result<u32, IoError> {
+        let json = json!({"ok": false, "error": code}).to_string();
+        self.write_json_response_with_status(error.code, error.status, request_headers, &json)
+            .await?;
+        Ok(error.code)
+    }
+
     async fn write_error(&mut self, error: &HttpError) -> Result<(), IoError> {
-        self.write_text_response(
+        self.write_error_with_headers(error, &[]).await
+    }
+
+    async fn write_error_with_headers(
+        &mut self,
+        error: &HttpError,
+        extra_headers: &[(&str, &str)],
+    ) -> Result<(), IoError> {
+        self.write_response_with_headers(
             error.code,
             error.status,
-            "text/html",
-            &format!("<h1>{} {}</h1>", error.code, error.status),
+            "text/html; charset=utf-8",
+            extra_headers,
+            format!("<h1>{} {}</h1>", error.code, error.status).as_bytes(),
         )
         .await
     }
 
+    /// Sends the events with Server-Sent Events until the client goes away. The stream starts with
+    /// the events missed since `since`, or a snapshot, so a client reconnecting after being dropped
+    /// for being too slow is up to date.
+    async fn stream_events(&mut self, since: Option<u64>) -> Result<(), IoError> {
+        self.keep_alive = false;
+        let header = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: close\r\nX-Accel-Buffering: no\r\n\r\n";
+        self.send(&[header.as_bytes()]).await?;
+        if self.head_request {
+            return Ok(());
+        }
+
+        let remote = self.remote();
+        eprintln!("{} : Events Opened", remote);
+        let mut subscription = self
+            .state
+            .hub
+            .subscribe(
+                &self.state.tweeps,
+                &self.state.date,
+                &self.state.image_list,
+                since,
+            )
+            .await;
+        let result = async {
+            let mut events: Vec<String> = vec![format!("retry: {}\n\n", EVENTS_RETRY_MS)];
+            events.extend(
+                subscription
+                    .events
+                    .iter()
+                    .map(|event| server_sent_event(event)),
+            );
+            loop {
+                let parts: Vec<&[u8]> = events.iter().map(|event| event.as_bytes()).collect();
+                self.send(&parts).await?;
+                events = match async_std::future::timeout(
+                    EVENTS_KEEPALIVE,
+                    subscription.receiver.next(),
+                )
+                .await
+                {
+                    Ok(Some(event)) => vec![server_sent_event(&event)],
+                    Ok(None) => {
+                        eprintln!("{} : Too far behind, closing the events", remote);
+                        return Ok(());
+                    }
+                    Err(_) => vec![": keepalive\n\n".to_string()],
+                };
+            }
+        }
+        .await;
+        eprintln!("{} : Events Closed", remote);
+        result
+    }
+
     async fn write_upgrade_response(&mut self, key: &[u8]) -> Result<(), IoError> {
         let to_hash = [key, b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11"].concat();
         let mut hasher = Sha1::new();
@@ -200,10 +736,9 @@ impl HttpConnection {
         let hash = hasher.finalize();
         let header = format!(
             "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
-            base64::encode(hash)
+            base64::engine::general_purpose::STANDARD.encode(hash)
         );
-        self.stream.write_all(header.as_bytes()).await?;
-        Ok(())
+        self.send(&[header.as_bytes()]).await
     }
 
     async fn handle_upgrade_request<'a>(
@@ -269,116 +804,888 @@ impl HttpConnection {
         }
     }
 
-    async fn handle_connection(mut self) -> Result<(), Box<dyn Error>> {
-        let mut request_buffer: Vec<u8> = Vec::new();
-        let (path, headers) = loop {
-            let mut buffer = [0u8; 512];
-
-            let read_size = self.stream.read(&mut buffer).await?;
-            request_buffer.extend_from_slice(&buffer[..read_size]);
-
-            let mut headers = [httparse::EMPTY_HEADER; 32];
-            let mut request = httparse::Request::new(&mut headers);
-            match request.parse(&request_buffer) {
-                Ok(httparse::Status::Complete(_)) => break (request.path, headers),
-                Ok(httparse::Status::Partial) => continue,
-                Err(e) => {
-                    eprintln!("{} : httparse error : {}", self.peer_addr, e);
-                    break (None, headers);
+    async fn handle_endpoint(
+        &mut self,
+        method: Method,
+        endpoint: Endpoint,
+        body: &[u8],
+        parameters: &[String],
+        query: &Query,
+        headers: &[httparse::Header<'_>],
+    ) -> Result<(u32, bool), IoError> {
+        let code = match endpoint {
+            Endpoint::Index => {
+                // Selecting a theme with the query parameter remembers it for the next visits
+                let mut cookies: Vec<String> = query
+                    .get("theme")
+                    .map(|_| {
+                        format!(
+                            "theme={}; Path={}/; Max-Age=31536000; SameSite=Lax",
+                            requested_theme(query, headers).name,
+                            self.state.proxy.base_path()
+                        )
+                    })
+                    .into_iter()
+                    .collect();
+                match query.get("pair") {
+                    // The URL printed with the PIN pairs the device directly, we redirect to
+                    // remove the PIN from the address bar and the history
+                    Some(pin) => {
+                        if let Ok(session) = self.state.pairing.pair(pin, self.client_ip) {
+                            cookies.push(session_cookie(
+                                &session,
+                                self.state.proxy.base_path(),
+                                self.secure,
+                            ));
+                        }
+                        let mut response_headers = vec![("Location", "./")];
+                        response_headers.extend(cookies.iter().map(|c| ("Set-Cookie", c.as_str())));
+                        self.write_response_with_headers(
+                            303,
+                            "See Other",
+                            "text/plain; charset=utf-8",
+                            &response_headers,
+                            b"",
+                        )
+                        .await?;
+                        303
+                    }
+                    None => {
+                        let response_headers: Vec<_> =
+                            cookies.iter().map(|c| ("Set-Cookie", c.as_str())).collect();
+                        match self.index_html() {
+                            Ok(index_html) => {
+                                self.write_embedded_resource(headers, index_html, &response_headers)
+                                    .await?
+                            }
+                            Err(e) => {
+                                eprintln!(
+                                    "{} : Unable to generate index.html : {}",
+                                    self.remote(),
+                                    e
+                                );
+                                self.write_error(&HTTP_500).await?;
+                                HTTP_500.code
+                            }
+                        }
+                    }
                 }
             }
-        };
-
-        let (code, upgraded);
-        if path.is_some() && path.unwrap().starts_with("/img/") {
-            if let Some(image_name) = path.unwrap().get(5..) {
-                if let Some(image_content) = self.image_list.get(image_name).cloned() {
-                    code = 200;
-                    self.write_response(200, "OK", "image/png", &image_content)
+            Endpoint::Pairing if method == Method::Post => {
+                #[derive(Deserialize)]
+                struct PairingRequest {
+                    pin: String,
+                }
+                let session = match serde_json::from_slice::<PairingRequest>(body) {
+                    Ok(request) => self.state.pairing.pair(&request.pin, self.client_ip),
+                    Err(_) => {
+                        self.write_error(&HTTP_400).await?;
+                        return Ok((HTTP_400.code, false));
+                    }
+                };
+                match session {
+                    Ok(session) => {
+                        self.access = Access::Paired;
+                        let cookie =
+                            session_cookie(&session, self.state.proxy.base_path(), self.secure);
+                        self.write_response_with_headers(
+                            200,
+                            "OK",
+                            "application/json; charset=utf-8",
+                            &[("Set-Cookie", &cookie)],
+                            json!({"access": self.access.name()}).to_string().as_bytes(),
+                        )
                         .await?;
+                        200
+                    }
+                    Err(PairingError::WrongPin) => {
+                        eprintln!("{} : Wrong pairing PIN", self.remote());
+                        self.write_error(&HTTP_403).await?;
+                        HTTP_403.code
+                    }
+                    Err(PairingError::TooManyAttempts(wait)) => {
+                        // Rounded up, a client retrying right on time must not be refused again
+                        let retry_after = (wait.as_secs() + 1).to_string();
+                        self.write_error_with_headers(&HTTP_429, &[("Retry-After", &retry_after)])
+                            .await?;
+                        HTTP_429.code
+                    }
+                }
+            }
+            Endpoint::Pairing => {
+                self.write_json_response(
+                    headers,
+                    &json!({"access": self.access.name()}).to_string(),
+                )
+                .await?;
+                200
+            }
+            Endpoint::IndexJs => {
+                self.write_embedded_resource(headers, &INDEX_JS, &[])
+                    .await?
+            }
+            Endpoint::ThemeCss => {
+                let css = requested_theme(query, headers).css(&*self.state.image_list.read().await);
+                let etag = format!("\"{}\"", content_hash(css.as_bytes()));
+                self.write_cached_response(
+                    headers,
+                    "text/css; charset=utf-8",
+                    CACHE_REVALIDATE,
+                    &etag,
+                    &[("Vary", "Cookie")],
+                    css.as_bytes(),
+                )
+                .await?
+            }
+            Endpoint::Image => {
+                let image = self
+                    .state
+                    .image_list
+                    .read()
+                    .await
+                    .get(&parameters[0])
+                    .map(|image| (image.data.clone(), image.hash.clone()));
+                if let Some((data, hash)) = image {
+                    // Only URLs including the hash of the content will never change
+                    let cache_control = if query.get("v") == Some(&hash) {
+                        CACHE_IMMUTABLE
+                    } else {
+                        CACHE_REVALIDATE
+                    };
+                    self.write_cached_response(
+                        headers,
+                        "image/png",
+                        cache_control,
+                        &format!("\"{}\"", hash),
+                        &[],
+                        &data,
+                    )
+                    .await?
                 } else {
-                    code = HTTP_404.code;
                     self.write_error(&HTTP_404).await?;
+                    HTTP_404.code
                 }
-            } else {
-                code = HTTP_404.code;
-                self.write_error(&HTTP_404).await?;
             }
-            upgraded = false;
-        } else if path == Some("/websocket") {
-            (code, upgraded) = self.handle_upgrade_request(&headers).await?;
-        } else {
-            enum HttpRoutingResult {
-                Ok(&'static str, &'static str),
-                Err(&'static HttpError),
+            Endpoint::Card => {
+                let tweep_id = parameters[0]
+                    .strip_suffix(".png")
+                    .and_then(|id| id.parse::<u32>().ok());
+                // Rendering takes a while, it is done on a blocking thread without holding the
+                // timeline so the game reader and the other clients don't wait for it
+                let tweep = match tweep_id {
+                    Some(id) => self
+                        .state
+                        .tweeps
+                        .lock()
+                        .await
+                        .iter()
+                        .find(|tweep| tweep.id == id)
+                        .cloned(),
+                    None => None,
+                };
+                let card = match tweep {
+                    Some(tweep) => {
+                        let date = *self.state.date.read().await;
+                        let images =
+                            card::card_images(&tweep, &*self.state.image_list.read().await);
+                        Some(
+                            task::spawn_blocking(move || {
+                                card::render_card(&tweep, date, &images).map_err(|e| e.to_string())
+                            })
+                            .await,
+                        )
+                    }
+                    None => None,
+                };
+                match card {
+                    Some(Ok(card)) => {
+                        let etag = format!("\"{}\"", content_hash(&card));
+                        self.write_cached_response(
+                            headers,
+                            "image/png",
+                            CACHE_REVALIDATE,
+                            &etag,
+                            &[],
+                            &card,
+                        )
+                        .await?
+                    }
+                    Some(Err(e)) => {
+                        eprintln!("{} : Unable to render card : {}", self.remote(), e);
+                        self.write_error(&HTTP_500).await?;
+                        HTTP_500.code
+                    }
+                    None => {
+                        self.write_error(&HTTP_404).await?;
+                        HTTP_404.code
+                    }
+                }
             }
-
-            let routing_result = match path {
-                Some("/") => HttpRoutingResult::Ok(include_str!("../res/index.html"), "text/html"),
-                Some("/index.js") => {
-                    HttpRoutingResult::Ok(include_str!("../res/index.js"), "text/javascript")
+            Endpoint::Authors => {
+                let authors: Vec<serde_json::Value> = self
+                    .state
+                    .authors
+                    .lock()
+                    .await
+                    .iter()
+                    .map(|author| author.to_json())
+                    .collect();
+                self.write_json_response(headers, &serde_json::Value::from(authors).to_string())
+                    .await?;
+                200
+            }
+            Endpoint::Author => {
+                let mut authors: Vec<serde_json::Value> = Vec::new();
+                {
+                    let locked_authors = self.state.authors.lock().await;
+                    let locked_tweeps = self.state.tweeps.lock().await;
+                    for author in locked_authors.find_by_username(&parameters[0]) {
+                        let author_tweeps: Vec<_> = locked_tweeps
+                            .iter()
+                            .filter(|tweep| author.tweep_ids.contains(&tweep.id))
+                            .collect();
+                        let mut author_json = author.to_json();
+                        author_json["tweeps"] = json!(author_tweeps);
+                        authors.push(author_json);
+                    }
                 }
-                Some(_) => HttpRoutingResult::Err(&HTTP_404),
-                None => HttpRoutingResult::Err(&HTTP_400),
-            };
-            code = match routing_result {
-                HttpRoutingResult::Ok(text, content_type) => {
-                    self.write_text_response(200, "OK", content_type, text)
-                        .await?;
+                if authors.is_empty() {
+                    self.write_error(&HTTP_404).await?;
+                    HTTP_404.code
+                } else {
+                    self.write_json_response(
+                        headers,
+                        &serde_json::Value::from(authors).to_string(),
+                    )
+                    .await?;
                     200
                 }
-                HttpRoutingResult::Err(e) => {
-                    self.write_error(e).await?;
-                    e.code
+            }
+            Endpoint::Tweeps => match TweepFilter::from_query(query) {
+                Some(filter) => {
+                    let tweeps_as_json = {
+                        let locked_tweeps = self.state.tweeps.lock().await;
+                        let tweeps: Vec<&Tweep> = locked_tweeps
+                            .iter()
+                            .filter(|tweep| filter.matches(tweep))
+                            .collect();
+                        json!(tweeps).to_string()
+                    };
+                    self.write_json_response(headers, &tweeps_as_json).await?;
+                    200
                 }
-            };
-            upgraded = false;
+                None => {
+                    self.write_error(&HTTP_400).await?;
+                    HTTP_400.code
+                }
+            },
+            Endpoint::Tweep => {
+                let tweep_as_json = match parameters[0].parse::<u32>() {
+                    Ok(id) => self
+                        .state
+                        .tweeps
+                        .lock()
+                        .await
+                        .iter()
+                        .find(|tweep| tweep.id == id)
+                        .map(|tweep| json!(tweep).to_string()),
+                    Err(_) => None,
+                };
+                match tweep_as_json {
+                    Some(tweep_as_json) => {
+                        self.write_json_response(headers, &tweep_as_json).await?;
+                        200
+                    }
+                    None => {
+                        self.write_error(&HTTP_404).await?;
+                        HTTP_404.code
+                    }
+                }
+            }
+            Endpoint::Events => {
+                self.stream_events(requested_since(query, headers)).await?;
+                200
+            }
+            Endpoint::Poll => {
+                let response = match query.get("session") {
+                    Some(session) => self
+                        .state
+                        .poll_sessions
+                        .poll(session)
+                        .await
+                        .map(|events| poll_response(session, false, &events)),
+                    None => {
+                        let (session, events, snapshot) = self
+                            .state
+                            .poll_sessions
+                            .create(
+                                &self.state.hub,
+                                &self.state.tweeps,
+                                &self.state.date,
+                                &self.state.image_list,
+                                requested_since(query, headers),
+                            )
+                            .await;
+                        Some(poll_response(&session, snapshot, &events))
+                    }
+                };
+                match response {
+                    Some(response) => {
+                        self.write_json_response(headers, &response).await?;
+                        200
+                    }
+                    None => {
+                        self.write_json_error(headers, &HTTP_404, "unknown_session")
+                            .await?
+                    }
+                }
+            }
+            Endpoint::Reply => {
+                #[derive(Deserialize)]
+                struct ReplyRequest {
+                    reply_id: u32,
+                }
+                if self.access != Access::Paired {
+                    return Ok((
+                        self.write_json_error(headers, &HTTP_403, "not_paired")
+                            .await?,
+                        false,
+                    ));
+                }
+                let (tweep_id, reply_id) = match (
+                    parameters[0].parse::<u32>(),
+                    serde_json::from_slice::<ReplyRequest>(body),
+                ) {
+                    (Ok(tweep_id), Ok(request)) => (tweep_id, request.reply_id),
+                    (Err(_), _) => {
+                        return Ok((
+                            self.write_json_error(headers, &HTTP_404, "unknown_tweep")
+                                .await?,
+                            false,
+                        ))
+                    }
+                    (_, Err(_)) => {
+                        return Ok((
+                            self.write_json_error(headers, &HTTP_400, "invalid_request")
+                                .await?,
+                            false,
+                        ))
+                    }
+                };
+                match game::send_reply(&self.state.tweeps, tweep_id, reply_id).await {
+                    Ok(()) => {
+                        eprintln!(
+                            "{} : Reply {} to tweep {}",
+                            self.remote(),
+                            reply_id,
+                            tweep_id
+                        );
+                        let json = json!({"ok": true, "tweep_id": tweep_id, "reply_id": reply_id});
+                        self.write_json_response(headers, &json.to_string()).await?;
+                        200
+                    }
+                    Err(e) => {
+                        let error = match e {
+                            ReplyError::UnknownTweep => &HTTP_404,
+                            ReplyError::UnknownReply => &HTTP_422,
+                            ReplyError::Io(_) => &HTTP_500,
+                        };
+                        eprintln!("{} : {}", self.remote(), e);
+                        self.write_json_error(headers, error, e.code()).await?
+                    }
+                }
+            }
+            Endpoint::State => {
+                let state_as_json = json!({
+                    "date": *self.state.date.read().await,
+                    "tweep_count": self.state.tweeps.lock().await.len(),
+                    "game": self.state.game_status.read().await.to_json(),
+                })
+                .to_string();
+                self.write_json_response(headers, &state_as_json).await?;
+                200
+            }
+            Endpoint::Qr => {
+                // The QR code contains the PIN, so only the computer running the game and devices
+                // already paired can display it
+                if self.access != Access::Paired
+                    && !self
+                        .state
+                        .proxy
+                        .is_local_request(self.peer_addr.ip(), headers)
+                {
+                    self.write_error(&HTTP_403).await?;
+                    return Ok((HTTP_403.code, false));
+                }
+                let scheme = if self.secure { "https" } else { "http" };
+                let host = self
+                    .state
+                    .proxy
+                    .forwarded_host(self.peer_addr.ip(), headers)
+                    .or_else(|| find_header(headers, "Host"))
+                    .map(|host| host.to_string())
+                    .unwrap_or_else(|| self.local_addr.to_string());
+                let url = format!("{}://{}{}/", scheme, host, self.state.proxy.base_path());
+                let pin = self.state.pairing.pin();
+                match qr::render_png(&qr::pairing_url(&url, pin.as_deref()))
+                    .map_err(|e| e.to_string())
+                {
+                    Ok(png) => {
+                        self.write_response_with_headers(
+                            200,
+                            "OK",
+                            "image/png",
+                            &[("Cache-Control", "no-store")],
+                            &png,
+                        )
+                        .await?;
+                        200
+                    }
+                    Err(e) => {
+                        eprintln!("{} : Unable to render QR code : {}", self.remote(), e);
+                        self.write_error(&HTTP_500).await?;
+                        HTTP_500.code
+                    }
+                }
+            }
+            Endpoint::WebSocket => {
+                self.since = requested_since(query, headers);
+                return self.handle_upgrade_request(headers).await;
+            }
+        };
+        Ok((code, false))
+    }
+
+    /// Requests coming from a page of another website are rejected, they could otherwise open a
+    /// WebSocket and reply in the game. Clients that aren't browsers usually don't send `Origin`.
+    fn is_allowed_origin(&self, headers: &[httparse::Header<'_>]) -> bool {
+        if let Some(host) = find_header(headers, "Host") {
+            if !self.state.allowed_hosts.check_host(host) {
+                eprintln!("{} : Rejected Host {:?}", self.remote(), host);
+                return false;
+            }
+        }
+        if let Some(origin) = find_header(headers, "Origin") {
+            if !self.state.allowed_hosts.check_origin(origin) {
+                eprintln!("{} : Rejected Origin {:?}", self.remote(), origin);
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn handle_request(
+        &mut self,
+        method: Option<&str>,
+        target: Option<&str>,
+        headers: &[httparse::Header<'_>],
+        body: &[u8],
+    ) -> Result<(u32, bool), IoError> {
+        self.head_request = false;
+        let (method, target) = match (method, target) {
+            (Some(method), Some(target)) => (method, target),
+            _ => {
+                self.write_error(&HTTP_400).await?;
+                return Ok((HTTP_400.code, false));
+            }
+        };
+        let method = match Method::parse(method) {
+            Some(m) => m,
+            None => {
+                self.write_error(&HTTP_501).await?;
+                return Ok((HTTP_501.code, false));
+            }
+        };
+        self.head_request = method == Method::Head;
+        let proxy = self.state.proxy.clone();
+        self.client_ip = proxy.client_ip(self.peer_addr.ip(), headers);
+        self.secure = proxy
+            .forwarded_https(self.peer_addr.ip(), headers)
+            .unwrap_or(self.stream.is_tls());
+
+        if !self.is_allowed_origin(headers) {
+            self.write_error(&HTTP_403).await?;
+            return Ok((HTTP_403.code, false));
         }
 
-        let user_agent = match headers
-            .iter()
-            .find(|header| header.name.to_lowercase() == "user-agent")
-        {
-            Some(h) => std::str::from_utf8(h.value).ok(),
-            None => None,
+        self.access = self.state.pairing.access(cookie(headers, SESSION_COOKIE));
+
+        let (path, query) = router::split_target(target);
+        let path = match proxy.strip_base_path(path) {
+            Some(path) => path,
+            // The relative URLs of the web client only work from the base path with its slash
+            None if path == proxy.base_path() => {
+                let location = match target.split_once('?') {
+                    Some((_, query)) => format!("{}/?{}", path, query),
+                    None => format!("{}/", path),
+                };
+                self.write_response_with_headers(
+                    308,
+                    "Permanent Redirect",
+                    "text/plain; charset=utf-8",
+                    &[("Location", &location)],
+                    b"",
+                )
+                .await?;
+                return Ok((308, false));
+            }
+            None => {
+                self.write_error(&HTTP_404).await?;
+                return Ok((HTTP_404.code, false));
+            }
         };
+        match router::route(method, path) {
+            RoutingResult::Found(endpoint, _)
+                if endpoint.reads_timeline() && self.access == Access::Denied =>
+            {
+                if endpoint.is_api() {
+                    let code = self
+                        .write_json_error(headers, &HTTP_403, "not_paired")
+                        .await?;
+                    return Ok((code, false));
+                }
+                self.write_error(&HTTP_403).await?;
+                Ok((HTTP_403.code, false))
+            }
+            RoutingResult::Found(endpoint, parameters) => {
+                self.handle_endpoint(method, endpoint, body, &parameters, &query, headers)
+                    .await
+            }
+            RoutingResult::MethodNotAllowed(allow) => {
+                self.write_error_with_headers(&HTTP_405, &[("Allow", &allow)])
+                    .await?;
+                Ok((HTTP_405.code, false))
+            }
+            RoutingResult::NotFound => {
+                self.write_error(&HTTP_404).await?;
+                Ok((HTTP_404.code, false))
+            }
+        }
+    }
+
+    /// Answers a request we won't process with `error` and closes the connection, the rest of the
+    /// stream can't be trusted anymore.
+    async fn reject_request(mut self, error: &HttpError) -> Result<(), Box<dyn Error>> {
         eprintln!(
-            "{} : {:?} {:?} : {}",
-            self.peer_addr, path, user_agent, code
+            "{} : Rejected : {} {}",
+            self.remote(),
+            error.code,
+            error.status
         );
+        self.keep_alive = false;
+        self.head_request = false;
+        io::timeout(REJECT_TIMEOUT, self.write_error(error)).await?;
+        // Gives the client some time to read the response before the socket is closed, it may
+        // still be sending the request
+        task::sleep(REJECT_LINGER).await;
+        Ok(())
+    }
 
-        if upgraded {
-            self.handle_websocket().await
-        } else {
-            Ok(())
+    async fn handle_connection(mut self) -> Result<(), Box<dyn Error>> {
+        let mut request_buffer: Vec<u8> = Vec::new();
+        loop {
+            let mut request_deadline =
+                (!request_buffer.is_empty()).then(|| Instant::now() + REQUEST_READ_TIMEOUT);
+            // Pipelined requests may already be in the buffer, so we only read from the socket
+            // when we don't have a complete one.
+            let request_len = loop {
+                match complete_request_len(&request_buffer) {
+                    Ok(Some(len)) => break len,
+                    Ok(None) => (),
+                    Err(error) => return self.reject_request(error).await,
+                }
+
+                let timeout = match request_deadline {
+                    Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                    None => KEEP_ALIVE_TIMEOUT,
+                };
+                let mut buffer = [0u8; 512];
+                let read_size = match io::timeout(timeout, self.stream.read(&mut buffer)).await {
+                    Ok(s) => s,
+                    // An idle connection is closed silently, the client will open a new one
+                    Err(e) if e.kind() == ErrorKind::TimedOut && request_deadline.is_none() => {
+                        return Ok(())
+                    }
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {
+                        return self.reject_request(&HTTP_408).await
+                    }
+                    Err(e) => return Err(Box::new(e)),
+                };
+                if read_size == 0 {
+                    return Ok(());
+                }
+                request_buffer.extend_from_slice(&buffer[..read_size]);
+                request_deadline.get_or_insert_with(|| Instant::now() + REQUEST_READ_TIMEOUT);
+            };
+
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut request = httparse::Request::new(&mut headers);
+            // Already parsed successfully by `complete_request_len`
+            let header_len = match request.parse(&request_buffer) {
+                Ok(httparse::Status::Complete(len)) => len,
+                _ => request_len,
+            };
+            let body = &request_buffer[header_len..request_len];
+            let (method, path) = (request.method, request.path);
+            self.keep_alive = wants_keep_alive(&request);
+
+            let (code, upgraded) = self
+                .handle_request(method, path, request.headers, body)
+                .await?;
+
+            let user_agent = find_header(request.headers, "User-Agent");
+            eprintln!(
+                "{} : {:?} {:?} {:?} : {}",
+                self.remote(),
+                method,
+                path,
+                user_agent,
+                code
+            );
+
+            if !self.keep_alive && !upgraded {
+                return Ok(());
+            }
+            if upgraded {
+                let leftover = request_buffer.split_off(request_len);
+                return self.handle_websocket(leftover).await;
+            }
+            request_buffer.drain(..request_len);
+        }
+    }
+}
+
+/// Messages a WebSocket client can send, tagged by their `type`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Picks the reply `reply_id` of the tweep `tweep_id` in the game
+    Reply { tweep_id: u32, reply_id: u32 },
+    /// Introduces the client, only logged for now
+    Hello {
+        client: Option<String>,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    /// Asks for the whole timeline again, e.g. when the client thinks it is out of sync
+    RequestSnapshot,
+    /// The tweeps up to `tweep_id` were read, accepted so clients can send it but not kept yet
+    MarkRead { tweep_id: u32 },
+    /// Answered with a `pong` event, for clients that can't send ping frames
+    Ping,
+    #[serde(other)]
+    Unknown,
+}
+
+/// Reads the messages of a WebSocket client until it goes away. Invalid messages are answered with
+/// an `error` event rather than closing the connection, the client would only reconnect.
+async fn read_websocket(
+    state: &ServerState,
+    access: Access,
+    mut read: stream::SplitStream<async_tungstenite::WebSocketStream<Stream>>,
+    mut answers: mpsc::Sender<Answer>,
+    snapshot_sending: &AtomicBool,
+    last_heard: &LastHeard,
+    remote: &str,
+) -> Result<(), Box<dyn Error>> {
+    while let Some(message) = read.next().await {
+        let valid_message = message?;
+        *last_heard.lock().unwrap() = Instant::now();
+        let message_str = match valid_message {
+            Message::Text(ref text) => text.as_str(),
+            // The pongs answering the pings of the sender only matter for the time they were
+            // received at, the client pings are answered by tungstenite
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_) => continue,
+            Message::Binary(_) => {
+                let error = events::error_event("invalid_message", "Messages must be JSON text");
+                answers.send(Answer::Event(error)).await?;
+                continue;
+            }
+        };
+        eprintln!("{} : {}", remote, message_str.trim());
+
+        // Some browsers (seen with Firefox Focus on Android) send their own keepalive as a "PING"
+        // text message instead of a ping frame. It still counts as activity like a pong, and gets an
+        // error event instead of dropping the connection.
+        let client_message: ClientMessage = match serde_json::from_str(message_str) {
+            Ok(m) => m,
+            Err(e) => {
+                let error = events::error_event("invalid_message", &e.to_string());
+                answers.send(Answer::Event(error)).await?;
+                continue;
+            }
+        };
+
+        let answer = match client_message {
+            ClientMessage::Reply { .. } if access != Access::Paired => Some(events::error_event(
+                "not_paired",
+                "The device must be paired to reply",
+            )),
+            ClientMessage::Reply { tweep_id, reply_id } => {
+                match game::send_reply(&state.tweeps, tweep_id, reply_id).await {
+                    Ok(()) => None,
+                    Err(e) => {
+                        eprintln!("{} : {} via websocket", remote, e);
+                        Some(events::error_event(e.code(), &e.to_string()))
+                    }
+                }
+            }
+            ClientMessage::Hello {
+                client,
+                capabilities,
+            } => {
+                eprintln!(
+                    "{} : Client {} with capabilities {:?}",
+                    remote,
+                    client.as_deref().unwrap_or("unknown"),
+                    capabilities
+                );
+                None
+            }
+            ClientMessage::RequestSnapshot if snapshot_sending.load(Ordering::Acquire) => {
+                Some(events::error_event(
+                    "snapshot_in_progress",
+                    "The previous snapshot is still being sent",
+                ))
+            }
+            ClientMessage::RequestSnapshot => {
+                snapshot_sending.store(true, Ordering::Release);
+                let subscription = state
+                    .hub
+                    .resubscribe(&state.tweeps, &state.date, &state.image_list)
+                    .await;
+                answers.send(Answer::Resubscribe(subscription)).await?;
+                None
+            }
+            ClientMessage::MarkRead { tweep_id } => {
+                let known = state.tweeps.lock().await.iter().any(|t| t.id == tweep_id);
+                (!known).then(|| {
+                    events::error_event(ReplyError::UnknownTweep.code(), "Unknown tweep id")
+                })
+            }
+            ClientMessage::Ping => Some(json!({"type": "pong"}).to_string()),
+            ClientMessage::Unknown => Some(events::error_event(
+                "unknown_message_type",
+                "Unknown message type",
+            )),
+        };
+        if let Some(answer) = answer {
+            answers.send(Answer::Event(answer)).await?;
         }
     }
+    Ok(())
+}
+
+/// Counts a connection as long as it is alive, WebSockets included, so a single client can't use
+/// all the tasks and sockets available.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn acquire(connection_count: &Arc<AtomicUsize>) -> Option<ConnectionSlot> {
+        connection_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < MAX_CONNECTIONS).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(connection_count.clone()))
+    }
 }
 
-pub async fn accept_connections(
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn accept_listener(
     listener: TcpListener,
-    write_streams: WriteStreams,
-    tweeps: Tweeps,
-    date: Date,
-    image_list: Arc<ImageList>,
+    state: ServerState,
+    connection_count: Arc<AtomicUsize>,
 ) {
     while let Ok((stream, peer_addr)) = listener.accept().await {
-        let write_streams_clone = write_streams.clone();
-        let tweeps_clone = tweeps.clone();
-        let date_clone = date.clone();
-        let image_list_clone = image_list.clone();
+        let slot = ConnectionSlot::acquire(&connection_count);
+        let state_clone = state.clone();
         task::spawn(async move {
-            let connection = HttpConnection::new(
-                stream,
-                peer_addr,
-                write_streams_clone,
-                tweeps_clone,
-                date_clone,
-                image_list_clone,
-            );
-            if let Err(error) = connection.handle_connection().await {
+            // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+            let peer_addr = SocketAddr::new(peer_addr.ip().to_canonical(), peer_addr.port());
+            let local_addr = match stream.local_addr() {
+                Ok(a) => SocketAddr::new(a.ip().to_canonical(), a.port()),
+                Err(e) => return eprintln!("{} : {}", peer_addr, e),
+            };
+            let stream = match &state_clone.tls {
+                Some(acceptor) => {
+                    match io::timeout(REQUEST_READ_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(s) => Stream::Tls(Box::new(s)),
+                        Err(e) => return eprintln!("{} : TLS handshake failed : {}", peer_addr, e),
+                    }
+                }
+                None => Stream::Plain(stream),
+            };
+            let connection = HttpConnection::new(stream, peer_addr, local_addr, state_clone);
+            let result = match slot {
+                Some(_slot) => connection.handle_connection().await,
+                None => connection.reject_request(&HTTP_503).await,
+            };
+            if let Err(error) = result {
                 eprintln!("{} : {}", peer_addr, error);
             }
         });
     }
 }
+
+/// Accepts connections on every listener, they share the limit of simultaneous connections.
+pub async fn accept_connections(listeners: Vec<TcpListener>, state: ServerState) {
+    let connection_count = Arc::new(AtomicUsize::new(0));
+    future::join_all(
+        listeners
+            .into_iter()
+            .map(|listener| accept_listener(listener, state.clone(), connection_count.clone())),
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::complete_request_len;
+
+    fn request_len(request: &str) -> Result<Option<usize>, u32> {
+        complete_request_len(request.as_bytes()).map_err(|error| error.code)
+    }
+
+    #[test]
+    fn bodies() {
+        let get = "GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(request_len(get), Ok(Some(get.len())));
+        assert_eq!(request_len(&format!("{}GET", get)), Ok(Some(get.len())));
+        assert_eq!(request_len("GET / HTTP/1.1\r\nHost: a\r\n"), Ok(None));
+
+        let post = "POST /api/pairing HTTP/1.1\r\nContent-Length: 4\r\n\r\n";
+        assert_eq!(request_len(post), Ok(None));
+        assert_eq!(
+            request_len(&format!("{}{{}}{{}}", post)),
+            Ok(Some(post.len() + 4))
+        );
+    }
+
+    #[test]
+    fn ambiguous_lengths() {
+        assert_eq!(
+            request_len(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\n\r\n"
+            ),
+            Err(501)
+        );
+        assert_eq!(
+            request_len("POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 2\r\n\r\n{}"),
+            Err(400)
+        );
+        assert_eq!(
+            request_len("POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\n{}"),
+            Err(400)
+        );
+        assert_eq!(
+            request_len("POST / HTTP/1.1\r\nContent-Length: 2, 2\r\n\r\n{}"),
+            Err(400)
+        );
+    }
+}
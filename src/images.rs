@@ -1,10 +1,17 @@
 use async_std::io;
+use async_std::sync::{Arc, RwLock};
+use async_std::task;
 
 use std::collections::HashMap;
 use std::error::Error;
+use std::io::{Error as IoError, ErrorKind};
 
 use futures::prelude::*;
 
+use super::config::Config;
+use super::http::content_hash;
+use super::theme::THEMES;
+
 struct ImageOffset {
     name: &'static str,
     x: u32,
@@ -12,10 +19,19 @@ struct ImageOffset {
     w: u32,
     h: u32,
     rotate: bool,
+    themed: bool,
 }
 
 impl ImageOffset {
-    const fn new(name: &'static str, x: u32, y: u32, w: u32, h: u32, rotate: bool) -> ImageOffset {
+    const fn new(
+        name: &'static str,
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+        rotate: bool,
+        themed: bool,
+    ) -> ImageOffset {
         ImageOffset {
             name,
             x,
@@ -23,23 +39,67 @@ impl ImageOffset {
             w,
             h,
             rotate,
+            themed,
         }
     }
 }
 
 const IMAGES_OFFSET: [ImageOffset; 9] = [
-    ImageOffset::new("bg", 176, 0, 613, 1090, false),
-    ImageOffset::new("header", 0, 0, 74, 1090, true),
-    ImageOffset::new("unsel", 74, 0, 93, 1090, true),
-    ImageOffset::new("sel_a", 1949, 7, 94, 294, true),
-    ImageOffset::new("sel_b", 2045, 11, 94, 286, true),
-    ImageOffset::new("sel_c", 2141, 7, 94, 288, true),
-    ImageOffset::new("sel_d", 2237, 7, 94, 276, true),
-    ImageOffset::new("reply", 1115, 1545, 334, 94, false),
-    ImageOffset::new("send", 1493, 1545, 334, 94, false),
+    ImageOffset::new("bg", 176, 0, 613, 1090, false, true),
+    ImageOffset::new("header", 0, 0, 74, 1090, true, true),
+    ImageOffset::new("unsel", 74, 0, 93, 1090, true, true),
+    ImageOffset::new("sel_a", 1949, 7, 94, 294, true, true),
+    ImageOffset::new("sel_b", 2045, 11, 94, 286, true, true),
+    ImageOffset::new("sel_c", 2141, 7, 94, 288, true, true),
+    ImageOffset::new("sel_d", 2237, 7, 94, 276, true, true),
+    ImageOffset::new("reply", 1115, 1545, 334, 94, false, false),
+    ImageOffset::new("send", 1493, 1545, 334, 94, false, false),
+];
+
+struct AvatarRow {
+    first_id: u32,
+    y: u32,
+    count: u32,
+}
+
+const AVATAR_SIZE: u32 = 152;
+const AVATAR_GUTTER: u32 = 1;
+const AVATAR_STRIDE: u32 = AVATAR_SIZE + 2 * AVATAR_GUTTER;
+const AVATAR_ROWS: [AvatarRow; 2] = [
+    AvatarRow {
+        first_id: 0,
+        y: 1895,
+        count: 26,
+    },
+    AvatarRow {
+        first_id: 26,
+        y: 1741,
+        count: 8,
+    },
 ];
 
-pub type ImageList = HashMap<String, Vec<u8>>;
+#[derive(Clone)]
+pub struct Image {
+    pub data: Vec<u8>,
+    pub hash: String,
+}
+
+impl Image {
+    pub fn new(data: Vec<u8>) -> Image {
+        let hash = content_hash(&data);
+        Image { data, hash }
+    }
+
+    /// URL of the image including the hash of its content, browsers can keep it in cache forever.
+    pub fn versioned_url(&self, name: &str) -> String {
+        format!("img/{}?v={}", name, self.hash)
+    }
+}
+
+pub type ImageList = HashMap<String, Image>;
+pub type Images = Arc<RwLock<ImageList>>;
+
+pub const ATLAS_NAME: &str = "ar_chip3.png";
 
 fn add_to_imagelist(
     image_list: &mut ImageList,
@@ -48,21 +108,18 @@ fn add_to_imagelist(
 ) -> Result<(), Box<dyn Error>> {
     let mut buff = std::io::Cursor::new(Vec::with_capacity(0x4000));
     image.write_to(&mut buff, image::ImageFormat::Png)?;
-    image_list.insert(name, buff.into_inner());
+    image_list.insert(name, Image::new(buff.into_inner()));
     Ok(())
 }
 
-pub async fn read_images_from_stdin() -> Result<ImageList, Box<dyn Error>> {
-    let mut stdin = io::stdin();
-
-    let mut size_buf = [0u8; 4];
-    stdin.read_exact(&mut size_buf).await?;
-    let size: usize = u32::from_ne_bytes(size_buf) as usize;
-
-    let mut buffer = vec![0u8; size];
-    stdin.read_exact(&mut buffer).await?;
+fn crop_atlas(
+    ar_chip3: &image::DynamicImage,
+    detect_avatar_grid: bool,
+) -> Result<ImageList, Box<dyn Error>> {
+    if detect_avatar_grid {
+        check_avatar_grid(ar_chip3);
+    }
 
-    let ar_chip3 = image::load_from_memory_with_format(&buffer, image::ImageFormat::Png)?;
     let mut image_list: ImageList = HashMap::new();
 
     for image_offset in IMAGES_OFFSET.iter() {
@@ -75,21 +132,154 @@ pub async fn read_images_from_stdin() -> Result<ImageList, Box<dyn Error>> {
         if image_offset.rotate {
             subimage = subimage.rotate270();
         }
-        add_to_imagelist(
-            &mut image_list,
-            format!("{}.png", image_offset.name),
-            subimage,
-        )?;
+        let name = format!("{}.png", image_offset.name);
+        if image_offset.themed {
+            for theme in THEMES.iter() {
+                if let Some(recoloured) = theme.recolour(&subimage) {
+                    add_to_imagelist(&mut image_list, theme.sprite_name(&name), recoloured)?;
+                }
+            }
+        }
+        add_to_imagelist(&mut image_list, name, subimage)?;
     }
 
-    for pfp_id in 0..26 {
-        let subimage = ar_chip3.crop_imm(1 + 154 * pfp_id, 1895, 152, 152);
-        add_to_imagelist(&mut image_list, format!("pfp{:02}.png", pfp_id), subimage)?;
-    }
-    for pfp_id in 26..34 {
-        let subimage = ar_chip3.crop_imm(1 + 154 * (pfp_id - 26), 1741, 152, 152);
-        add_to_imagelist(&mut image_list, format!("pfp{:02}.png", pfp_id), subimage)?;
+    for avatar_row in AVATAR_ROWS.iter() {
+        for index in 0..avatar_row.count {
+            let subimage = ar_chip3.crop_imm(
+                AVATAR_GUTTER + AVATAR_STRIDE * index,
+                avatar_row.y,
+                AVATAR_SIZE,
+                AVATAR_SIZE,
+            );
+            let pfp_id = avatar_row.first_id + index;
+            add_to_imagelist(&mut image_list, format!("pfp{:02}.png", pfp_id), subimage)?;
+        }
     }
 
     Ok(image_list)
 }
+
+fn is_transparent(atlas: &image::RgbaImage, x: u32, y: u32) -> bool {
+    atlas.get_pixel_checked(x, y).is_none_or(|p| p[3] == 0)
+}
+
+/// Checks if an avatar tile starts at (`x`, `y`) : it must be surrounded by fully transparent
+/// gutters and contain at least one visible pixel.
+fn is_avatar_tile(atlas: &image::RgbaImage, x: u32, y: u32) -> bool {
+    if x + AVATAR_SIZE + AVATAR_GUTTER > atlas.width()
+        || y + AVATAR_SIZE + AVATAR_GUTTER > atlas.height()
+    {
+        return false;
+    }
+    let gutters_transparent = (x - AVATAR_GUTTER..=x + AVATAR_SIZE).all(|gx| {
+        is_transparent(atlas, gx, y - AVATAR_GUTTER) && is_transparent(atlas, gx, y + AVATAR_SIZE)
+    }) && (y..y + AVATAR_SIZE).all(|gy| {
+        is_transparent(atlas, x - AVATAR_GUTTER, gy) && is_transparent(atlas, x + AVATAR_SIZE, gy)
+    });
+    gutters_transparent
+        && (y..y + AVATAR_SIZE)
+            .any(|ty| (x..x + AVATAR_SIZE).any(|tx| !is_transparent(atlas, tx, ty)))
+}
+
+/// Scans the left edge of the atlas for rows of avatar tiles and returns the `y` coordinate and the
+/// amount of tiles of each row found, sorted by `y`.
+fn detect_avatar_grid(ar_chip3: &image::DynamicImage) -> Vec<(u32, u32)> {
+    let atlas = ar_chip3.to_rgba8();
+    let mut rows = Vec::new();
+    let mut y = AVATAR_GUTTER;
+    while y + AVATAR_SIZE + AVATAR_GUTTER <= atlas.height() {
+        if !is_avatar_tile(&atlas, AVATAR_GUTTER, y) {
+            y += 1;
+            continue;
+        }
+        let count = (0..)
+            .take_while(|index| is_avatar_tile(&atlas, AVATAR_GUTTER + AVATAR_STRIDE * index, y))
+            .count() as u32;
+        rows.push((y, count));
+        y += AVATAR_STRIDE;
+    }
+    rows
+}
+
+fn check_avatar_grid(ar_chip3: &image::DynamicImage) {
+    let detected = detect_avatar_grid(ar_chip3);
+    let mut expected: Vec<(u32, u32)> = AVATAR_ROWS.iter().map(|row| (row.y, row.count)).collect();
+    expected.sort();
+
+    let detected_slots: u32 = detected.iter().map(|(_, count)| count).sum();
+    if detected == expected {
+        eprintln!("Avatar grid detected with {} slots", detected_slots);
+    } else {
+        eprintln!(
+            "WARN : The avatar grid of the atlas doesn't match the built-in layout, avatars might be wrong"
+        );
+        eprintln!(
+            "WARN : Expected rows (y, count) {:?} but detected {:?} ({} slots)",
+            expected, detected, detected_slots
+        );
+    }
+}
+
+pub async fn read_images_from_stdin(config: &Config) -> Result<ImageList, Box<dyn Error>> {
+    let mut stdin = io::stdin();
+
+    let mut size_buf = [0u8; 4];
+    stdin.read_exact(&mut size_buf).await?;
+    let size: usize = u32::from_ne_bytes(size_buf) as usize;
+
+    let mut buffer = vec![0u8; size];
+    stdin.read_exact(&mut buffer).await?;
+
+    let ar_chip3 = image::load_from_memory_with_format(&buffer, image::ImageFormat::Png)?;
+    crop_atlas(&ar_chip3, config.detect_avatar_grid)
+}
+
+/// Texture names end up in the `/img/` URLs, so we only accept a conservative set of characters.
+fn is_valid_texture_name(name: &str) -> bool {
+    name.ends_with(".png")
+        && !name.starts_with('/')
+        && !name.contains("..")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '/'))
+}
+
+/// Adds or replaces the texture `name` sent by the game while running and returns the versioned URLs
+/// of all the images that changed. Sending the atlas again under the name [`ATLAS_NAME`] crops every
+/// sprite and avatar from it as during startup.
+pub async fn add_texture(
+    images: &Images,
+    config: &Config,
+    name: &str,
+    data: Vec<u8>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    if !is_valid_texture_name(name) {
+        return Err(Box::new(IoError::new(
+            ErrorKind::InvalidData,
+            "Invalid texture name",
+        )));
+    }
+
+    // Decoding the texture and cropping and recolouring the atlas take a while, they are done on a
+    // blocking thread so the other tasks aren't delayed
+    let name = name.to_string();
+    let detect_avatar_grid = config.detect_avatar_grid;
+    let new_images = task::spawn_blocking(move || {
+        let texture = image::load_from_memory_with_format(&data, image::ImageFormat::Png)
+            .map_err(|e| e.to_string())?;
+        if name == ATLAS_NAME {
+            crop_atlas(&texture, detect_avatar_grid).map_err(|e| e.to_string())
+        } else {
+            Ok(HashMap::from([(name, Image::new(data))]))
+        }
+    })
+    .await?;
+
+    let mut changed: Vec<String> = new_images
+        .iter()
+        .map(|(name, image)| image.versioned_url(name))
+        .collect();
+    changed.sort();
+    images.write().await.extend(new_images);
+    Ok(changed)
+}
@@ -0,0 +1,121 @@
+use async_std::net::TcpListener;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+
+// Same backlog as the standard library
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Parses the listen addresses given on the command line. Each argument may hold several addresses
+/// separated by commas, since the game only passes `twipoSynchroListenAddress` as is.
+pub fn parse_addresses<I: Iterator<Item = String>>(
+    args: I,
+) -> Result<Vec<SocketAddr>, AddrParseError> {
+    let mut addresses: Vec<SocketAddr> = Vec::new();
+    for arg in args {
+        for address in arg.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            let address = address.parse()?;
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+    }
+    Ok(addresses)
+}
+
+/// `[::]` also accepts IPv4 connections, unless `0.0.0.0` is listened on the same port.
+fn is_dual_stack(address: SocketAddr, addresses: &[SocketAddr]) -> bool {
+    address.ip().is_unspecified()
+        && address.is_ipv6()
+        && !addresses
+            .iter()
+            .any(|a| a.is_ipv4() && a.ip().is_unspecified() && a.port() == address.port())
+}
+
+/// Binds `address`, one of `addresses`. The socket is created by hand since the IPv6 only option
+/// must be set before binding and doesn't have the same default on every OS.
+pub fn bind(address: SocketAddr, addresses: &[SocketAddr]) -> Result<TcpListener, std::io::Error> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!is_dual_stack(address, addresses))?;
+    }
+    // Allows a restart while the connections of the previous run are in TIME_WAIT, Windows lets
+    // another process steal the port with this option so it is left alone there
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(TcpListener::from(std::net::TcpListener::from(socket)))
+}
+
+/// Addresses other devices can use to reach a server listening on `address`, loopback and IPv6
+/// link-local addresses are left out since they are useless on a phone.
+pub fn reachable_addresses(address: SocketAddr, addresses: &[SocketAddr]) -> Vec<IpAddr> {
+    let ip = address.ip();
+    if !ip.is_unspecified() {
+        return vec![ip];
+    }
+    let dual_stack = is_dual_stack(address, addresses);
+    match local_ip_address::list_afinet_netifas() {
+        Ok(addrs) => {
+            let mut reachable: Vec<IpAddr> = Vec::new();
+            for (_, addr) in addrs {
+                let usable = match addr {
+                    IpAddr::V4(_) => address.is_ipv4() || dual_stack,
+                    IpAddr::V6(v6) => address.is_ipv6() && !v6.is_unicast_link_local(),
+                } && !addr.is_loopback();
+                if usable && !reachable.contains(&addr) {
+                    reachable.push(addr);
+                }
+            }
+            reachable
+        }
+        Err(e) => {
+            eprintln!("Unable to get your local IP addresses : {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_dual_stack, parse_addresses};
+
+    use std::net::SocketAddr;
+
+    fn parse(args: &[&str]) -> Vec<SocketAddr> {
+        parse_addresses(args.iter().map(|a| a.to_string())).unwrap()
+    }
+
+    #[test]
+    fn parse_lists() {
+        assert_eq!(
+            parse(&["127.0.0.1:80, [::1]:80", "0.0.0.0:81,,127.0.0.1:80"]),
+            vec![
+                "127.0.0.1:80".parse().unwrap(),
+                "[::1]:80".parse().unwrap(),
+                "0.0.0.0:81".parse().unwrap(),
+            ]
+        );
+        assert!(parse(&[]).is_empty());
+        assert!(parse_addresses(["127.0.0.1".to_string()].into_iter()).is_err());
+        assert!(parse_addresses(["localhost:80".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn dual_stack() {
+        let any6: SocketAddr = "[::]:80".parse().unwrap();
+        let any4: SocketAddr = "0.0.0.0:80".parse().unwrap();
+        let other4: SocketAddr = "0.0.0.0:81".parse().unwrap();
+        assert!(is_dual_stack(any6, &[any6]));
+        assert!(is_dual_stack(any6, &[any6, other4]));
+        assert!(!is_dual_stack(any6, &[any6, any4]));
+        assert!(!is_dual_stack(any4, &[any4]));
+        assert!(!is_dual_stack("[::1]:80".parse().unwrap(), &[]));
+    }
+}
@@ -1,28 +1,37 @@
-use async_std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use async_std::net::{SocketAddr, TcpListener};
 use async_std::sync::{Arc, Mutex, RwLock};
 use async_std::task;
 
 use futures::prelude::*;
 
 use std::io::{Error as IoError, ErrorKind};
-use std::str::FromStr;
 
+pub mod authors;
+pub mod card;
+pub mod config;
+pub mod encoding;
+pub mod events;
 pub mod game;
+pub mod hosts;
 pub mod http;
 pub mod images;
+pub mod listen;
+pub mod mdns;
+pub mod pairing;
+pub mod proxy;
+pub mod qr;
+pub mod router;
+pub mod theme;
+pub mod tls;
 
 async fn async_main() -> Result<(), IoError> {
-    let listen_address_str = match std::env::args().nth(1) {
-        Some(a) => a,
-        None => {
+    let listen_addresses = match listen::parse_addresses(std::env::args().skip(1)) {
+        Ok(a) if a.is_empty() => {
             return Err(IoError::new(
                 ErrorKind::InvalidInput,
                 "Expected listen address as first argument",
             ))
         }
-    };
-
-    let listen_address = match SocketAddr::from_str(&listen_address_str) {
         Ok(a) => a,
         Err(e) => {
             eprintln!("{}", e);
@@ -33,7 +42,9 @@ async fn async_main() -> Result<(), IoError> {
         }
     };
 
-    let image_list = match images::read_images_from_stdin().await {
+    let config = Arc::new(config::Config::load());
+
+    let image_list = match images::read_images_from_stdin(&config).await {
         Ok(i) => i,
         Err(e) => {
             eprintln!("{}", e);
@@ -44,41 +55,124 @@ async fn async_main() -> Result<(), IoError> {
         }
     };
 
-    let listener = TcpListener::bind(&listen_address).await?;
-    eprintln!("**** Start apprication on {} ****", &listen_address);
+    let tls = if config.tls {
+        match tls::load_acceptor(&config, hosts::server_names(&listen_addresses, &config)) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    "Unable to load the TLS certificate",
+                ));
+            }
+        }
+    } else {
+        None
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+
+    let mut listeners: Vec<TcpListener> = Vec::with_capacity(listen_addresses.len());
+    for listen_address in listen_addresses.iter() {
+        match listen::bind(*listen_address, &listen_addresses) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                eprintln!("Unable to listen on {} : {}", listen_address, e);
+                return Err(e);
+            }
+        }
+    }
+    for listen_address in listen_addresses.iter() {
+        eprintln!("**** Start apprication on {} ****", listen_address);
+    }
+    let proxy = Arc::new(proxy::ReverseProxy::new(&config));
 
     // We assume that if the user changed the listen address, they know what they're doing and must
     // be able to find the correct address themselves.
-    if listen_address.ip() == IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)) {
-        match local_ip_address::list_afinet_netifas() {
-            Ok(addrs) => {
-                for (_, addr) in addrs.iter() {
-                    if let std::net::IpAddr::V4(v4addr) = addr {
-                        if !v4addr.is_loopback() {
-                            eprintln!("   * http://{}:{}/", v4addr, listen_address.port());
-                        }
-                    }
-                }
+    let mut urls = Vec::new();
+    for listen_address in listen_addresses.iter() {
+        if !listen_address.ip().is_unspecified() {
+            continue;
+        }
+        for ip in listen::reachable_addresses(*listen_address, &listen_addresses) {
+            // SocketAddr puts IPv6 addresses between brackets as URLs expect
+            urls.push(format!(
+                "{}://{}{}/",
+                scheme,
+                SocketAddr::new(ip, listen_address.port()),
+                proxy.base_path()
+            ));
+        }
+    }
+    let pairing = Arc::new(pairing::Pairing::new(&config));
+    pairing.start(urls);
+
+    // The daemon stops answering when dropped, so it must live as long as the server
+    let _mdns_daemon = if config.mdns {
+        match mdns::advertise(
+            &config,
+            &listen_addresses,
+            tls.is_some(),
+            &format!("{}/", proxy.base_path()),
+        ) {
+            Ok(daemon) => {
+                eprintln!(
+                    "   * {}://{}:{}{}/",
+                    scheme,
+                    mdns::hostname(&config),
+                    listen_addresses[0].port(),
+                    proxy.base_path()
+                );
+                Some(daemon)
             }
             Err(e) => {
-                eprintln!("Unable to get your local IPv4 addresses : {}", e);
+                eprintln!("Unable to advertise the server with mDNS : {}", e);
+                None
             }
         }
-    }
+    } else {
+        None
+    };
 
-    let write_streams: http::WriteStreams = Arc::new(Mutex::new(Vec::new()));
+    let hub = Arc::new(events::Hub::new(events::hello_event(
+        &config,
+        tls.is_some(),
+        http::CLIENT_HASH,
+    )));
     let tweeps: game::Tweeps = Arc::new(Mutex::new(Vec::new()));
     let date: game::Date = Arc::new(RwLock::new(0));
+    let authors: authors::Authors = Arc::new(Mutex::new(authors::AuthorDirectory::default()));
+    let images: images::Images = Arc::new(RwLock::new(image_list));
+    let game_status: game::Status = Arc::new(RwLock::new(game::GameStatus::default()));
+    let allowed_hosts = Arc::new(hosts::AllowedHosts::new(
+        &listen_addresses,
+        &config,
+        tls.is_some(),
+    ));
+
+    let state = http::ServerState {
+        hub: hub.clone(),
+        poll_sessions: Arc::new(events::PollSessions::default()),
+        keepalive: events::Keepalive::new(&config),
+        tweeps: tweeps.clone(),
+        date: date.clone(),
+        game_status: game_status.clone(),
+        authors: authors.clone(),
+        image_list: images.clone(),
+        allowed_hosts,
+        pairing,
+        proxy,
+        tls,
+    };
 
     futures::select!(
-        _ = http::accept_connections(listener,
-                                     write_streams.clone(),
-                                     tweeps.clone(),
-                                     date.clone(),
-                                     Arc::new(image_list)).fuse() => Ok(()),
-        e = game::read_stdin(write_streams.clone(),
+        _ = http::accept_connections(listeners, state).fuse() => Ok(()),
+        e = game::read_stdin(hub.clone(),
                              tweeps.clone(),
-                             date.clone()).fuse() => e,
+                             date.clone(),
+                             authors.clone(),
+                             images.clone(),
+                             config.clone(),
+                             game_status.clone()).fuse() => e,
     )
 }
 
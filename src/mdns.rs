@@ -0,0 +1,108 @@
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+
+use std::net::{IpAddr, SocketAddr};
+
+use super::config::Config;
+use super::listen;
+
+const INSTANCE_NAME: &str = "Twipo Synchro";
+
+/// Name of the server on the local network, e.g. `twipo.local`.
+pub fn hostname(config: &Config) -> String {
+    format!("{}.local", config.mdns_hostname)
+}
+
+/// Addresses announced for the hostname, the same ones as the URLs printed on startup for the
+/// listen addresses using `port`. Loopback is only announced when listening on it explicitly, e.g.
+/// `127.0.0.1`, which is only useful for testing. Listening on `0.0.0.0` leaves it out.
+fn announced_addresses(listen_addresses: &[SocketAddr], port: u16) -> Vec<IpAddr> {
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for address in listen_addresses.iter().filter(|a| a.port() == port) {
+        for ip in listen::reachable_addresses(*address, listen_addresses) {
+            if !addresses.contains(&ip) {
+                addresses.push(ip);
+            }
+        }
+    }
+    addresses
+}
+
+/// Advertises the web server with multicast DNS and a DNS-SD service record. The daemon answers
+/// from its own thread until it is dropped. A service record only has one port, the one of the first
+/// listen address is advertised.
+pub fn advertise(
+    config: &Config,
+    listen_addresses: &[SocketAddr],
+    tls: bool,
+    path: &str,
+) -> Result<ServiceDaemon, mdns_sd::Error> {
+    let port = listen_addresses[0].port();
+    let addresses = announced_addresses(listen_addresses, port);
+    let daemon = ServiceDaemon::new()?;
+    if addresses
+        .iter()
+        .any(|addr| addr.is_loopback() && addr.is_ipv4())
+    {
+        daemon.enable_interface(IfKind::LoopbackV4)?;
+    }
+    if addresses
+        .iter()
+        .any(|addr| addr.is_loopback() && addr.is_ipv6())
+    {
+        daemon.enable_interface(IfKind::LoopbackV6)?;
+    }
+
+    let service_type = if tls {
+        "_https._tcp.local."
+    } else {
+        "_http._tcp.local."
+    };
+    let service = ServiceInfo::new(
+        service_type,
+        INSTANCE_NAME,
+        &format!("{}.", hostname(config)),
+        &addresses[..],
+        port,
+        &[("path", path)][..],
+    )?;
+    daemon.register(service)?;
+    Ok(daemon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mdns_sd::ServiceEvent;
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn advertise_on_loopback() {
+        let config = Config::default();
+        let listen_address: SocketAddr = "127.0.0.1:18181".parse().unwrap();
+        let _server = advertise(&config, &[listen_address], false, "/").unwrap();
+
+        let client = ServiceDaemon::new().unwrap();
+        client.enable_interface(IfKind::LoopbackV4).unwrap();
+        let events = client.browse("_http._tcp.local.").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let resolved = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(remaining) {
+                Ok(ServiceEvent::ServiceResolved(info))
+                    if info.get_hostname() == "twipo.local." && info.get_port() == 18181 =>
+                {
+                    break info
+                }
+                Ok(_) => continue,
+                Err(e) => panic!("twipo.local was not resolved : {}", e),
+            }
+        };
+        assert!(resolved
+            .get_addresses()
+            .contains(&IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(resolved.get_property_val_str("path"), Some("/"));
+        let _ = client.shutdown();
+    }
+}
@@ -0,0 +1,318 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use super::config::{self, Config};
+use super::qr;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SESSION_COOKIE: &str = "twipo_session";
+const SECRET_FILE: &str = "session.key";
+const SECRET_SIZE: usize = 32;
+const SESSION_ID_SIZE: usize = 16;
+const PIN_DIGITS: u32 = 6;
+// Wrong PINs a client can enter in a row before it must wait, the wait then doubles with each new
+// one. The PIN stays the same, so a client guessing wrong doesn't lock the owner out.
+const FREE_FAILED_ATTEMPTS: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+// The wrong PINs of a client are forgotten once it stopped guessing for this long
+const FAILURES_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Paired,
+    ReadOnly,
+    Denied,
+}
+
+impl Access {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Access::Paired => "paired",
+            Access::ReadOnly => "read_only",
+            Access::Denied => "denied",
+        }
+    }
+}
+
+pub fn random_bytes(size: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; size];
+    getrandom::getrandom(&mut bytes).expect("Unable to get random bytes from the OS");
+    bytes
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn new_pin() -> String {
+    let random = u64::from_ne_bytes(random_bytes(8).try_into().unwrap());
+    format!(
+        "{:0width$}",
+        random % 10u64.pow(PIN_DIGITS),
+        width = PIN_DIGITS as usize
+    )
+}
+
+/// Wrong PINs entered by a client, see `FREE_FAILED_ATTEMPTS`.
+struct Failures {
+    count: u32,
+    // In the past until the client entered too many wrong PINs
+    locked_until: Instant,
+}
+
+/// Clients are throttled by IP address, IPv6 ones by /64 since a single device can pick any address
+/// of its network.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !0xffff_ffff_ffff_ffffu128)),
+        ip => ip,
+    }
+}
+
+pub enum PairingError {
+    WrongPin,
+    /// The client entered too many wrong PINs and must wait this long before trying again
+    TooManyAttempts(Duration),
+}
+
+/// Devices must enter the PIN printed in the console before they can reply. A paired device gets a
+/// session cookie signed with a secret kept next to the executable, so it stays paired when the game
+/// is restarted.
+pub struct Pairing {
+    enabled: bool,
+    read_only_unpaired: bool,
+    secret: Vec<u8>,
+    pin: Mutex<Option<String>>,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    // Printed with their QR code every time the PIN changes, since the QR codes contain it
+    urls: OnceLock<Vec<String>>,
+}
+
+/// The secret is created on the first start, if it can't be saved the devices will have to be paired
+/// again after a restart.
+fn load_secret() -> Vec<u8> {
+    let path = config::data_path(SECRET_FILE);
+    if let Some(secret) = path
+        .as_ref()
+        .and_then(|p| std::fs::read(p).ok())
+        .filter(|secret| secret.len() == SECRET_SIZE)
+    {
+        return secret;
+    }
+
+    let secret = random_bytes(SECRET_SIZE);
+    match path.map(|p| config::write_private_file(&p, &secret)) {
+        Some(Ok(())) => (),
+        Some(Err(e)) => eprintln!("WARN : Unable to save the pairing secret : {}", e),
+        None => eprintln!("WARN : Unable to find where to save the pairing secret"),
+    }
+    secret
+}
+
+impl Pairing {
+    pub fn new(config: &Config) -> Pairing {
+        Pairing {
+            enabled: config.pairing,
+            read_only_unpaired: config.read_only_unpaired,
+            secret: if config.pairing {
+                load_secret()
+            } else {
+                Vec::new()
+            },
+            pin: Mutex::new(None),
+            failures: Mutex::new(HashMap::new()),
+            urls: OnceLock::new(),
+        }
+    }
+
+    /// Generates the first PIN and prints it with `urls`, it is called after the startup banner.
+    pub fn start(&self, urls: Vec<String>) {
+        let _ = self.urls.set(urls);
+        let pin = self.enabled.then(new_pin);
+        *self.pin.lock().unwrap() = pin.clone();
+        self.announce(pin.as_deref());
+    }
+
+    /// Prints the PIN and the URLs of the server with their QR codes, which pair the device at once.
+    fn announce(&self, pin: Option<&str>) {
+        if let Some(pin) = pin {
+            eprintln!("   Pairing PIN : {}", pin);
+        }
+        for url in self.urls.get().into_iter().flatten() {
+            eprintln!("   * {}", url);
+            match qr::render_terminal(&qr::pairing_url(url, pin)) {
+                Ok(code) => eprint!("{}", code),
+                Err(e) => eprintln!("Unable to render the QR code : {}", e),
+            }
+        }
+    }
+
+    /// Replaces the PIN and prints the new one, the previous QR codes don't work anymore.
+    fn rotate(&self, pin: &mut Option<String>) {
+        let new_pin = new_pin();
+        self.announce(Some(&new_pin));
+        *pin = Some(new_pin);
+    }
+
+    /// The current PIN, `None` when pairing is disabled.
+    pub fn pin(&self) -> Option<String> {
+        self.pin.lock().unwrap().clone()
+    }
+
+    fn sign(&self, session_id: &[u8]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(session_id);
+        mac
+    }
+
+    fn new_session(&self) -> String {
+        let session_id = random_bytes(SESSION_ID_SIZE);
+        let signature = self.sign(&session_id).finalize().into_bytes();
+        format!("{}.{}", to_hex(&session_id), to_hex(&signature))
+    }
+
+    fn is_valid_session(&self, session: &str) -> bool {
+        let Some((session_id, signature)) = session.split_once('.') else {
+            return false;
+        };
+        match (from_hex(session_id), from_hex(signature)) {
+            (Some(session_id), Some(signature)) => {
+                self.sign(&session_id).verify_slice(&signature).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the value of a new session cookie if `pin` is the current PIN. A PIN can only be
+    /// used once, a new one is printed after a success. A `client` that entered too many wrong PINs
+    /// must wait before its next one is even checked.
+    pub fn pair(&self, pin: &str, client: IpAddr) -> Result<String, PairingError> {
+        let now = Instant::now();
+        let key = client_key(client);
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, failures| now < failures.locked_until + FAILURES_EXPIRY);
+        if let Some(locked_until) = failures.get(&key).map(|f| f.locked_until) {
+            if now < locked_until {
+                return Err(PairingError::TooManyAttempts(locked_until - now));
+            }
+        }
+
+        let mut current = self.pin.lock().unwrap();
+        if current
+            .as_deref()
+            .is_some_and(|current| pin.trim() == current)
+        {
+            eprintln!("New device paired");
+            failures.remove(&key);
+            self.rotate(&mut current);
+            return Ok(self.new_session());
+        }
+        let client_failures = failures.entry(key).or_insert(Failures {
+            count: 0,
+            locked_until: now,
+        });
+        client_failures.count += 1;
+        if let Some(extra) = client_failures.count.checked_sub(FREE_FAILED_ATTEMPTS) {
+            let lockout = LOCKOUT
+                .saturating_mul(2u32.saturating_pow(extra))
+                .min(MAX_LOCKOUT);
+            client_failures.locked_until = now + lockout;
+            eprintln!(
+                "WARN : Too many wrong pairing PINs from {}, ignoring it for {} seconds",
+                client,
+                lockout.as_secs()
+            );
+        }
+        Err(PairingError::WrongPin)
+    }
+
+    /// Access given to a request with the session cookie `session`.
+    pub fn access(&self, session: Option<&str>) -> Access {
+        if !self.enabled || session.is_some_and(|s| self.is_valid_session(s)) {
+            Access::Paired
+        } else if self.read_only_unpaired {
+            Access::ReadOnly
+        } else {
+            Access::Denied
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pairing, PairingError, FREE_FAILED_ATTEMPTS, LOCKOUT};
+
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::{Mutex, OnceLock};
+
+    fn pairing() -> Pairing {
+        Pairing {
+            enabled: true,
+            read_only_unpaired: false,
+            secret: vec![0; 32],
+            pin: Mutex::new(Some("123456".to_string())),
+            failures: Mutex::new(HashMap::new()),
+            urls: OnceLock::new(),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn wrong_pins_lock_the_client_out() {
+        let pairing = pairing();
+        for _ in 0..FREE_FAILED_ATTEMPTS {
+            assert!(matches!(
+                pairing.pair("000000", ip("192.0.2.1")),
+                Err(PairingError::WrongPin)
+            ));
+        }
+        // Even the right PIN is ignored, but it is still the same one
+        match pairing.pair("123456", ip("192.0.2.1")) {
+            Err(PairingError::TooManyAttempts(wait)) => assert!(wait <= LOCKOUT),
+            _ => panic!("the client should be locked out"),
+        }
+        assert!(matches!(
+            pairing.pair("123456", ip("::ffff:192.0.2.1")),
+            Err(PairingError::TooManyAttempts(_))
+        ));
+        assert_eq!(pairing.pin().as_deref(), Some("123456"));
+
+        assert!(pairing.pair("123456", ip("192.0.2.2")).is_ok());
+        assert_ne!(pairing.pin().as_deref(), Some("123456"));
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_network() {
+        let pairing = pairing();
+        for i in 0..FREE_FAILED_ATTEMPTS {
+            let _ = pairing.pair("000000", ip(&format!("2001:db8::{}", i + 1)));
+        }
+        assert!(matches!(
+            pairing.pair("123456", ip("2001:db8::ffff")),
+            Err(PairingError::TooManyAttempts(_))
+        ));
+        assert!(pairing.pair("123456", ip("2001:db8:0:1::1")).is_ok());
+    }
+}
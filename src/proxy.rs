@@ -0,0 +1,281 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use super::config::Config;
+use super::http::find_header;
+
+/// Settings for a server published behind a reverse proxy. Only the proxies listed in the
+/// configuration are believed when they tell who the client is, anyone else could pretend to be the
+/// computer running the game.
+pub struct ReverseProxy {
+    // Empty or starting with a slash, without the trailing one
+    base_path: String,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+/// `twipo`, `/twipo` and `/twipo/` all give `/twipo`, the root gives an empty path. Only unreserved
+/// characters are accepted since the path is written as is in the HTML and the cookies.
+fn normalize_base_path(path: &str) -> Option<String> {
+    let path = path.trim().trim_matches('/');
+    if path.is_empty() {
+        return Some(String::new());
+    }
+    let valid_characters = path
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"-._~/".contains(&b));
+    let valid_segments = path
+        .split('/')
+        .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    (valid_characters && valid_segments).then(|| format!("/{}", path))
+}
+
+/// Parses a node of `Forwarded` or `X-Forwarded-For`, e.g. `192.0.2.1`, `"[2001:db8::1]:4711"` or
+/// `192.0.2.1:80`. Obfuscated and `unknown` nodes give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(v6) = node.strip_prefix('[') {
+        return v6.split_once(']')?.0.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+/// Values of the `key` parameter in each element of a `Forwarded` header, elements without it are
+/// skipped.
+fn forwarded_values<'a>(header: &'a str, key: &str) -> Vec<&'a str> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value.trim().trim_matches('"'))
+        })
+        .collect()
+}
+
+/// Last value of a `X-Forwarded-*` header, the one added by the proxy closest to the server.
+fn last_value(header: &str) -> Option<&str> {
+    header
+        .rsplit(',')
+        .map(str::trim)
+        .find(|value| !value.is_empty())
+}
+
+impl ReverseProxy {
+    pub fn new(config: &Config) -> ReverseProxy {
+        let base_path = normalize_base_path(&config.base_path).unwrap_or_else(|| {
+            eprintln!("WARN : Ignoring invalid base path {:?}", config.base_path);
+            String::new()
+        });
+        let trusted_proxies = config
+            .trusted_proxies
+            .iter()
+            .filter_map(|proxy| match proxy.trim().parse::<IpAddr>() {
+                Ok(ip) => Some(ip.to_canonical()),
+                Err(_) => {
+                    eprintln!("WARN : Ignoring invalid trusted proxy {:?}", proxy);
+                    None
+                }
+            })
+            .collect();
+        ReverseProxy {
+            base_path,
+            trusted_proxies,
+        }
+    }
+
+    /// Prefix of every route, empty when the server is at the root.
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    /// Removes the base path from `path`, `None` if it is outside of it. The base path itself
+    /// without the trailing slash is also outside, the client must be redirected so its relative
+    /// URLs work.
+    pub fn strip_base_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(self.base_path.as_str())
+            .filter(|path| path.starts_with('/'))
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.contains(&ip)
+    }
+
+    /// Address of the client that sent the request through `peer`. The forwarded addresses are
+    /// read from the closest proxy to the farthest one and the first one that isn't trusted is the
+    /// client. An obfuscated address gives the unspecified address, which is never trusted.
+    pub fn client_ip(&self, peer: IpAddr, headers: &[httparse::Header]) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let nodes: Vec<Option<IpAddr>> = match (
+            find_header(headers, "Forwarded"),
+            find_header(headers, "X-Forwarded-For"),
+        ) {
+            (Some(forwarded), _) => forwarded_values(forwarded, "for")
+                .into_iter()
+                .map(parse_node)
+                .collect(),
+            (None, Some(forwarded_for)) => forwarded_for.split(',').map(parse_node).collect(),
+            (None, None) => return peer,
+        };
+
+        let mut client = peer;
+        for node in nodes.into_iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            client = node
+                .map(|ip| ip.to_canonical())
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        }
+        client
+    }
+
+    /// Whether the request comes straight from a program of this computer. A loopback peer may be a
+    /// reverse proxy relaying someone else, so any forwarding header or configured proxy rules it out.
+    pub fn is_local_request(&self, peer: IpAddr, headers: &[httparse::Header]) -> bool {
+        let forwarded = headers.iter().any(|header| {
+            let name = header.name.to_ascii_lowercase();
+            name == "forwarded" || name == "x-real-ip" || name.starts_with("x-forwarded-")
+        });
+        self.trusted_proxies.is_empty() && peer.is_loopback() && !forwarded
+    }
+
+    /// Whether the client used HTTPS to reach a trusted proxy, `None` if `peer` isn't one or didn't
+    /// tell.
+    pub fn forwarded_https(&self, peer: IpAddr, headers: &[httparse::Header]) -> Option<bool> {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+        let proto = match find_header(headers, "Forwarded") {
+            Some(forwarded) => forwarded_values(forwarded, "proto").pop(),
+            None => find_header(headers, "X-Forwarded-Proto").and_then(last_value),
+        }?;
+        Some(proto.eq_ignore_ascii_case("https"))
+    }
+
+    /// Host the client used to reach a trusted proxy, `None` if `peer` isn't one or didn't tell.
+    pub fn forwarded_host<'a>(
+        &self,
+        peer: IpAddr,
+        headers: &[httparse::Header<'a>],
+    ) -> Option<&'a str> {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+        match find_header(headers, "Forwarded") {
+            Some(forwarded) => forwarded_values(forwarded, "host").pop(),
+            None => find_header(headers, "X-Forwarded-Host").and_then(last_value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forwarded_values, normalize_base_path, parse_node, ReverseProxy};
+    use crate::config::Config;
+
+    use std::net::IpAddr;
+
+    fn proxy(trusted_proxies: &[&str]) -> ReverseProxy {
+        ReverseProxy::new(&Config {
+            trusted_proxies: trusted_proxies.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn header<'a>(name: &'a str, value: &'a str) -> httparse::Header<'a> {
+        httparse::Header {
+            name,
+            value: value.as_bytes(),
+        }
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node(" 192.0.2.1:80"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(
+            parse_node("\"[2001:db8::1]:4711\""),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("\"[2001:db8::1]\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("unknown"), None);
+    }
+
+    #[test]
+    fn forwarded() {
+        let header = r#"for=192.0.2.1;proto=http, For="[2001:db8::1]:4711";by=_proxy, proto=https"#;
+        assert_eq!(
+            forwarded_values(header, "for"),
+            vec!["192.0.2.1", "[2001:db8::1]:4711"]
+        );
+        assert_eq!(forwarded_values(header, "proto"), vec!["http", "https"]);
+        assert!(forwarded_values(header, "host").is_empty());
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        let headers = [header("X-Forwarded-For", "192.0.2.1")];
+        assert_eq!(
+            proxy(&[]).client_ip(ip("10.0.0.1"), &headers),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            proxy(&["10.0.0.2"]).client_ip(ip("10.0.0.1"), &headers),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn no_header() {
+        assert_eq!(
+            proxy(&["10.0.0.1"]).client_ip(ip("10.0.0.1"), &[]),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_for_chain() {
+        let proxy = proxy(&["10.0.0.1", "10.0.0.2"]);
+        let headers = [header(
+            "X-Forwarded-For",
+            "203.0.113.9, 192.0.2.1, 10.0.0.2",
+        )];
+        assert_eq!(proxy.client_ip(ip("10.0.0.1"), &headers), ip("192.0.2.1"));
+        let headers = [header("X-Forwarded-For", "10.0.0.2")];
+        assert_eq!(proxy.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded_ipv6_and_obfuscated() {
+        let proxy = proxy(&["::1"]);
+        let headers = [
+            header("Forwarded", r#"for="[2001:db8::1]:4711""#),
+            header("X-Forwarded-For", "192.0.2.1"),
+        ];
+        assert_eq!(proxy.client_ip(ip("::1"), &headers), ip("2001:db8::1"));
+        let headers = [header("Forwarded", "for=_hidden")];
+        assert_eq!(proxy.client_ip(ip("::1"), &headers), ip("0.0.0.0"));
+        let headers = [header("Forwarded", "for=\"[::ffff:192.0.2.1]\"")];
+        assert_eq!(proxy.client_ip(ip("::1"), &headers), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn base_paths() {
+        assert_eq!(normalize_base_path("/twipo/").as_deref(), Some("/twipo"));
+        assert_eq!(normalize_base_path("a/b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_base_path("/").as_deref(), Some(""));
+        assert_eq!(normalize_base_path("a/../b"), None);
+        assert_eq!(normalize_base_path("a b"), None);
+    }
+}
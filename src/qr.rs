@@ -0,0 +1,64 @@
+use image::{GrayImage, ImageFormat, Luma};
+use qrcode::{Color, QrCode};
+
+use std::error::Error;
+
+// The specification asks for 4 modules, but 2 are enough for phones and keep the console readable
+const TERMINAL_QUIET_ZONE: i32 = 2;
+const PNG_QUIET_ZONE: u32 = 4;
+const PNG_MODULE_SIZE: u32 = 8;
+
+/// URL opening the web client and pairing the device at the same time when a PIN is available.
+pub fn pairing_url(url: &str, pin: Option<&str>) -> String {
+    match pin {
+        Some(pin) => format!("{}?pair={}", url, pin),
+        None => url.to_string(),
+    }
+}
+
+fn is_dark(code: &QrCode, x: i32, y: i32) -> bool {
+    let width = code.width() as i32;
+    (0..width).contains(&x)
+        && (0..width).contains(&y)
+        && code[(x as usize, y as usize)] == Color::Dark
+}
+
+/// Renders `data` with half blocks, each character holds two modules stacked vertically. Consoles
+/// usually draw light text on a dark background, so the light modules are the ones drawn.
+pub fn render_terminal(data: &str) -> Result<String, Box<dyn Error>> {
+    let code = QrCode::new(data)?;
+    let size = code.width() as i32 + TERMINAL_QUIET_ZONE;
+    let mut output = String::new();
+    for y in (-TERMINAL_QUIET_ZONE..size).step_by(2) {
+        for x in -TERMINAL_QUIET_ZONE..size {
+            let top = !is_dark(&code, x, y);
+            let bottom = y + 1 < size && !is_dark(&code, x, y + 1);
+            output.push(match (top, bottom) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+pub fn render_png(data: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let code = QrCode::new(data)?;
+    let size = (code.width() as u32 + 2 * PNG_QUIET_ZONE) * PNG_MODULE_SIZE;
+    let image = GrayImage::from_fn(size, size, |x, y| {
+        let module_x = (x / PNG_MODULE_SIZE) as i32 - PNG_QUIET_ZONE as i32;
+        let module_y = (y / PNG_MODULE_SIZE) as i32 - PNG_QUIET_ZONE as i32;
+        if is_dark(&code, module_x, module_y) {
+            Luma([0x00])
+        } else {
+            Luma([0xFF])
+        }
+    });
+
+    let mut buff = std::io::Cursor::new(Vec::with_capacity(0x1000));
+    image.write_to(&mut buff, ImageFormat::Png)?;
+    Ok(buff.into_inner())
+}
@@ -0,0 +1,344 @@
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+}
+
+impl Method {
+    pub fn parse(method: &str) -> Option<Method> {
+        match method {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "OPTIONS" => Some(Method::Options),
+            "PATCH" => Some(Method::Patch),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+/// Every page handled by the server, the HTTP connection matches on it to build the response.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Endpoint {
+    Index,
+    IndexJs,
+    ThemeCss,
+    Image,
+    Card,
+    Authors,
+    Author,
+    Tweeps,
+    Tweep,
+    Reply,
+    State,
+    Pairing,
+    Qr,
+    Events,
+    Poll,
+    WebSocket,
+}
+
+impl Endpoint {
+    /// Endpoints exposing the timeline are only available to paired devices, unless the server
+    /// allows unpaired ones to read it.
+    pub fn reads_timeline(&self) -> bool {
+        matches!(
+            self,
+            Endpoint::Card
+                | Endpoint::Authors
+                | Endpoint::Author
+                | Endpoint::Tweeps
+                | Endpoint::Tweep
+                | Endpoint::State
+                | Endpoint::Events
+                | Endpoint::Poll
+                | Endpoint::WebSocket
+        )
+    }
+
+    /// Endpoints under `/api`, their errors are JSON objects with a machine-readable code.
+    pub fn is_api(&self) -> bool {
+        matches!(
+            self,
+            Endpoint::Authors
+                | Endpoint::Author
+                | Endpoint::Tweeps
+                | Endpoint::Tweep
+                | Endpoint::Reply
+                | Endpoint::State
+                | Endpoint::Pairing
+                | Endpoint::Poll
+        )
+    }
+}
+
+pub struct Route {
+    methods: &'static [Method],
+    pattern: &'static str,
+    endpoint: Endpoint,
+}
+
+impl Route {
+    const fn new(methods: &'static [Method], pattern: &'static str, endpoint: Endpoint) -> Route {
+        Route {
+            methods,
+            pattern,
+            endpoint,
+        }
+    }
+
+    /// `HEAD` is accepted by every route accepting `GET`, the body is simply not sent.
+    fn allows(&self, method: Method) -> bool {
+        self.methods.contains(&method)
+            || (method == Method::Head && self.methods.contains(&Method::Get))
+    }
+}
+
+/// In the patterns, a `:name` segment captures a single segment of the path and a trailing `*name`
+/// captures everything left, including the slashes.
+const ROUTES: [Route; 16] = [
+    Route::new(&[Method::Get], "/", Endpoint::Index),
+    Route::new(&[Method::Get], "/index.js", Endpoint::IndexJs),
+    Route::new(&[Method::Get], "/theme.css", Endpoint::ThemeCss),
+    Route::new(&[Method::Get], "/img/*name", Endpoint::Image),
+    Route::new(&[Method::Get], "/card/:name", Endpoint::Card),
+    Route::new(&[Method::Get], "/api/authors", Endpoint::Authors),
+    Route::new(&[Method::Get], "/api/authors/:username", Endpoint::Author),
+    Route::new(&[Method::Get], "/api/tweeps", Endpoint::Tweeps),
+    Route::new(&[Method::Get], "/api/tweeps/:id", Endpoint::Tweep),
+    Route::new(&[Method::Post], "/api/tweeps/:id/reply", Endpoint::Reply),
+    Route::new(&[Method::Get], "/api/state", Endpoint::State),
+    Route::new(
+        &[Method::Get, Method::Post],
+        "/api/pairing",
+        Endpoint::Pairing,
+    ),
+    Route::new(&[Method::Get], "/qr.png", Endpoint::Qr),
+    Route::new(&[Method::Get], "/api/poll", Endpoint::Poll),
+    Route::new(&[Method::Get], "/events", Endpoint::Events),
+    Route::new(&[Method::Get], "/websocket", Endpoint::WebSocket),
+];
+
+pub enum RoutingResult {
+    /// The parameters captured by the pattern, percent-decoded, in order.
+    Found(Endpoint, Vec<String>),
+    /// The path exists but not with this method, holds the value of the `Allow` header.
+    MethodNotAllowed(String),
+    NotFound,
+}
+
+pub fn percent_decode(input: &str) -> Option<String> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = (bytes.next()? as char).to_digit(16)?;
+            let low = (bytes.next()? as char).to_digit(16)?;
+            output.push((high << 4 | low) as u8);
+        } else {
+            output.push(byte);
+        }
+    }
+    String::from_utf8(output).ok()
+}
+
+/// Parameters of the query string, decoded. Only the first value of a key is kept.
+#[derive(Default)]
+pub struct Query {
+    parameters: Vec<(String, String)>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Query {
+        // `+` is how forms encode spaces in query strings, it must be replaced before decoding since
+        // `%2B` is a real `+`
+        let decode = |s: &str| percent_decode(&s.replace('+', " "));
+        let parameters = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Some((decode(key)?, decode(value)?))
+            })
+            .collect();
+        Query { parameters }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Splits the target of a request in its path, still encoded, and its query.
+pub fn split_target(target: &str) -> (&str, Query) {
+    match target.split_once('?') {
+        Some((path, query)) => (path, Query::parse(query)),
+        None => (target, Query::default()),
+    }
+}
+
+/// Matches `path` against `pattern`, segments are decoded one by one so an encoded slash can't
+/// change the structure of the path.
+fn match_pattern(pattern: &str, path: &str) -> Option<Vec<String>> {
+    let mut parameters = Vec::new();
+    let mut path_segments = path.strip_prefix('/')?.split('/');
+    for pattern_segment in pattern.strip_prefix('/')?.split('/') {
+        if pattern_segment.starts_with('*') {
+            let rest: Vec<String> = path_segments.map(percent_decode).collect::<Option<_>>()?;
+            if rest.iter().all(|segment| segment.is_empty()) {
+                return None;
+            }
+            parameters.push(rest.join("/"));
+            return Some(parameters);
+        }
+
+        let path_segment = path_segments.next()?;
+        if pattern_segment.starts_with(':') {
+            if path_segment.is_empty() {
+                return None;
+            }
+            parameters.push(percent_decode(path_segment)?);
+        } else if pattern_segment != path_segment {
+            return None;
+        }
+    }
+    match path_segments.next() {
+        Some(_) => None,
+        None => Some(parameters),
+    }
+}
+
+pub fn route(method: Method, path: &str) -> RoutingResult {
+    let mut allowed: Vec<Method> = Vec::new();
+    for route in ROUTES.iter() {
+        if let Some(parameters) = match_pattern(route.pattern, path) {
+            if route.allows(method) {
+                return RoutingResult::Found(route.endpoint, parameters);
+            }
+            allowed.extend_from_slice(route.methods);
+        }
+    }
+
+    if allowed.is_empty() {
+        return RoutingResult::NotFound;
+    }
+    if allowed.contains(&Method::Get) {
+        allowed.push(Method::Head);
+    }
+    let allow: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+    RoutingResult::MethodNotAllowed(allow.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_static_pattern() {
+        assert_eq!(match_pattern("/", "/"), Some(vec![]));
+        assert_eq!(match_pattern("/api/tweeps", "/api/tweeps"), Some(vec![]));
+        assert_eq!(match_pattern("/api/tweeps", "/api/tweeps/"), None);
+        assert_eq!(match_pattern("/api/tweeps", "/api"), None);
+        assert_eq!(match_pattern("/api/tweeps", "api/tweeps"), None);
+    }
+
+    #[test]
+    fn match_segment_parameter() {
+        assert_eq!(
+            match_pattern("/api/tweeps/:id/reply", "/api/tweeps/12/reply"),
+            Some(vec!["12".to_string()])
+        );
+        assert_eq!(match_pattern("/api/tweeps/:id", "/api/tweeps/"), None);
+        assert_eq!(match_pattern("/api/tweeps/:id", "/api/tweeps/1/2"), None);
+        // An encoded slash stays inside the segment
+        assert_eq!(
+            match_pattern("/api/authors/:username", "/api/authors/a%2Fb"),
+            Some(vec!["a/b".to_string()])
+        );
+        assert_eq!(
+            match_pattern("/api/authors/:username", "/api/authors/%zz"),
+            None
+        );
+    }
+
+    #[test]
+    fn match_trailing_parameter() {
+        assert_eq!(
+            match_pattern("/img/*name", "/img/dark/bg.png"),
+            Some(vec!["dark/bg.png".to_string()])
+        );
+        assert_eq!(match_pattern("/img/*name", "/img/"), None);
+        assert_eq!(match_pattern("/img/*name", "/img"), None);
+    }
+
+    #[test]
+    fn api_endpoints() {
+        for route in ROUTES.iter() {
+            assert_eq!(
+                route.endpoint.is_api(),
+                route.pattern.starts_with("/api/"),
+                "{}",
+                route.pattern
+            );
+        }
+    }
+
+    #[test]
+    fn route_methods() {
+        assert!(matches!(
+            route(Method::Head, "/index.js"),
+            RoutingResult::Found(Endpoint::IndexJs, _)
+        ));
+        match route(Method::Post, "/api/tweeps") {
+            RoutingResult::MethodNotAllowed(allow) => assert_eq!(allow, "GET, HEAD"),
+            _ => panic!("POST /api/tweeps must not be allowed"),
+        }
+        assert!(matches!(
+            route(Method::Get, "/nothing"),
+            RoutingResult::NotFound
+        ));
+    }
+
+    #[test]
+    fn parse_query() {
+        let query = Query::parse("a=1&b=two+words&c=%2B&a=2&flag&&d=%zz");
+        assert_eq!(query.get("a"), Some("1"));
+        assert_eq!(query.get("b"), Some("two words"));
+        assert_eq!(query.get("c"), Some("+"));
+        assert_eq!(query.get("flag"), Some(""));
+        assert_eq!(query.get("d"), None);
+        assert_eq!(query.get("e"), None);
+    }
+
+    #[test]
+    fn split_target_query() {
+        let (path, query) = split_target("/api/tweeps?tab=1");
+        assert_eq!(path, "/api/tweeps");
+        assert_eq!(query.get("tab"), Some("1"));
+        let (path, query) = split_target("/");
+        assert_eq!(path, "/");
+        assert_eq!(query.get("tab"), None);
+    }
+}
@@ -0,0 +1,90 @@
+use image::DynamicImage;
+
+use super::images::ImageList;
+
+pub struct Theme {
+    pub name: &'static str,
+    recolour: Option<fn(&DynamicImage) -> DynamicImage>,
+    css_variables: &'static [(&'static str, &'static str)],
+}
+
+fn recolour_dark(image: &DynamicImage) -> DynamicImage {
+    // Inverting the sprite turns the bright background and boxes dark but also replaces every hue
+    // by its complementary colour. Rotating the hue back keeps the tabs recognisable.
+    let mut inverted = image.clone();
+    inverted.invert();
+    inverted.huerotate(180)
+}
+
+pub const THEMES: [Theme; 2] = [
+    Theme {
+        name: "light",
+        recolour: None,
+        css_variables: &[
+            ("text-color", "#404040"),
+            ("author-color", "#314981"),
+            ("ruby-color", "blue"),
+            ("details-color", "#A0A0A0"),
+            ("border-color", "gray"),
+            ("tweep-background", "transparent"),
+            ("notification-color", "#404040"),
+            ("notification-background", "#A0A0A0"),
+        ],
+    },
+    Theme {
+        name: "dark",
+        recolour: Some(recolour_dark),
+        css_variables: &[
+            ("text-color", "#D0D0D0"),
+            ("author-color", "#8FA8E0"),
+            ("ruby-color", "#80A0FF"),
+            ("details-color", "#808080"),
+            ("border-color", "#505050"),
+            ("tweep-background", "rgba(0, 0, 0, 0.4)"),
+            ("notification-color", "#D0D0D0"),
+            ("notification-background", "#404040"),
+        ],
+    },
+];
+
+impl Theme {
+    pub fn default_theme() -> &'static Theme {
+        &THEMES[0]
+    }
+
+    pub fn from_name(name: &str) -> Option<&'static Theme> {
+        THEMES.iter().find(|theme| theme.name == name)
+    }
+
+    /// Name of the variant of the sprite `name` in the image list. The default theme uses the
+    /// sprites cropped from the atlas as is, so its names are left untouched.
+    pub fn sprite_name(&self, name: &str) -> String {
+        match self.recolour {
+            Some(_) => format!("{}/{}", self.name, name),
+            None => name.to_string(),
+        }
+    }
+
+    pub fn recolour(&self, image: &DynamicImage) -> Option<DynamicImage> {
+        self.recolour.map(|recolour| recolour(image))
+    }
+
+    /// The background uses its versioned URL when the image is known, it can then stay in cache.
+    pub fn css(&self, image_list: &ImageList) -> String {
+        let mut css = String::from(":root {\n");
+        for (name, value) in self.css_variables.iter() {
+            css += &format!("\t--{}: {};\n", name, value);
+        }
+        let bg_name = self.sprite_name("bg.png");
+        let bg_url = match image_list.get(&bg_name) {
+            Some(image) => image.versioned_url(&bg_name),
+            None => format!("img/{}", bg_name),
+        };
+        css += &format!(
+            "\t--sprite-prefix: \"{}\";\n\t--bg-image: url(\"{}\");\n}}\n",
+            self.sprite_name(""),
+            bg_url
+        );
+        css
+    }
+}
@@ -0,0 +1,142 @@
+use async_std::net::TcpStream;
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use futures_rustls::rustls::ServerConfig;
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+
+use sha2::{Digest, Sha256};
+
+use std::error::Error;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use super::config::{self, Config};
+
+const CERTIFICATE_FILE: &str = "tls_cert.pem";
+const KEY_FILE: &str = "tls_key.pem";
+
+/// Connection accepted by the server, the HTTP and WebSocket code doesn't care if it is encrypted.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Stream {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Stream::Tls(_))
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, IoError>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_close(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_close(cx),
+        }
+    }
+}
+
+fn missing_path_error() -> IoError {
+    IoError::new(
+        ErrorKind::NotFound,
+        "Unable to find the folder of the server executable",
+    )
+}
+
+/// Relative paths of the configuration are relative to the folder of the server executable.
+fn configured_path(path: &Option<String>, default: &str) -> Result<PathBuf, IoError> {
+    config::data_path(path.as_deref().unwrap_or(default)).ok_or_else(missing_path_error)
+}
+
+/// Generates a self-signed certificate valid for `server_names` and saves it, browsers will show a
+/// warning the first time since no authority signed it.
+fn generate_certificate(
+    certificate_path: &Path,
+    key_path: &Path,
+    server_names: Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    eprintln!(
+        "Generating a self-signed TLS certificate for {}",
+        server_names.join(", ")
+    );
+    let certified_key = rcgen::generate_simple_self_signed(server_names)?;
+    std::fs::write(certificate_path, certified_key.cert.pem())?;
+    config::write_private_file(key_path, certified_key.key_pair.serialize_pem().as_bytes())?;
+    Ok(())
+}
+
+/// Loads the certificate and key set in the configuration, or the self-signed ones stored next to
+/// the executable which are generated on the first run.
+pub fn load_acceptor(
+    config: &Config,
+    server_names: Vec<String>,
+) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let user_provided = config.tls_certificate.is_some() || config.tls_key.is_some();
+    let certificate_path = configured_path(&config.tls_certificate, CERTIFICATE_FILE)?;
+    let key_path = configured_path(&config.tls_key, KEY_FILE)?;
+    let missing = !certificate_path.is_file() || !key_path.is_file();
+    if !user_provided && missing {
+        generate_certificate(&certificate_path, &key_path, server_names)?;
+    }
+
+    let certificates: Vec<CertificateDer> = rustls_pemfile::certs(&mut std::io::BufReader::new(
+        std::fs::File::open(&certificate_path)?,
+    ))
+    .collect::<Result<_, _>>()?;
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut std::io::BufReader::new(
+        std::fs::File::open(&key_path)?,
+    ))?
+    .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "No private key found"))?;
+    let fingerprint = certificates.first().map(|certificate| {
+        Sha256::digest(certificate)
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":")
+    });
+
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+    eprintln!("TLS certificate loaded from {}", certificate_path.display());
+    if let Some(fingerprint) = fingerprint {
+        eprintln!("   SHA-256 fingerprint : {}", fingerprint);
+    }
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}